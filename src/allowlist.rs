@@ -0,0 +1,104 @@
+use std::{
+    collections::HashSet,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::mac::MacAddress;
+use crate::metrics::{self, labelset, metric, MetricsWriter};
+
+/// Restricts which gateways may post measurements and which tags are exported.
+///
+/// Without a list, every gateway or tag is allowed.
+#[derive(Debug, Default)]
+pub struct Allowlist {
+    gateways: Option<HashSet<MacAddress>>,
+    tags: Option<HashSet<MacAddress>>,
+    dropped_messages: AtomicU64,
+    dropped_tags: AtomicU64,
+}
+
+impl Allowlist {
+    pub fn new(gateways: Option<Vec<MacAddress>>, tags: Option<Vec<MacAddress>>) -> Self {
+        Self {
+            gateways: gateways.map(HashSet::from_iter),
+            tags: tags.map(HashSet::from_iter),
+            dropped_messages: AtomicU64::new(0),
+            dropped_tags: AtomicU64::new(0),
+        }
+    }
+
+    /// Checks whether messages from `gw_mac` are accepted. Rejected messages are counted.
+    pub fn allows_gateway(&self, gw_mac: MacAddress) -> bool {
+        let allowed = self
+            .gateways
+            .as_ref()
+            .is_none_or(|gateways| gateways.contains(&gw_mac));
+        if !allowed {
+            self.dropped_messages.fetch_add(1, Ordering::Relaxed);
+        }
+        allowed
+    }
+
+    /// Checks whether the tag `mac` is exported. Rejected tags are counted.
+    pub fn allows_tag(&self, mac: MacAddress) -> bool {
+        let allowed = self.tags.as_ref().is_none_or(|tags| tags.contains(&mac));
+        if !allowed {
+            self.dropped_tags.fetch_add(1, Ordering::Relaxed);
+        }
+        allowed
+    }
+
+    pub fn collect_metrics(&self, metrics: &mut MetricsWriter) {
+        metrics.push(
+            &metrics::INGEST_DROPPED_MESSAGES,
+            &metric(metrics::INGEST_DROPPED_MESSAGES.name)
+                .labels(&labelset().label("reason", "unknown_gateway"))
+                .value(self.dropped_messages.load(Ordering::Relaxed)),
+        );
+        metrics.push(
+            &metrics::INGEST_DROPPED_TAGS,
+            &metric(metrics::INGEST_DROPPED_TAGS.name)
+                .labels(&labelset().label("reason", "unknown_tag"))
+                .value(self.dropped_tags.load(Ordering::Relaxed)),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mac(s: &str) -> MacAddress {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_no_lists_allow_everything() {
+        let allowlist = Allowlist::default();
+        assert!(allowlist.allows_gateway(mac("AA:BB:CC:DD:EE:FF")));
+        assert!(allowlist.allows_tag(mac("DD:19:92:CB:60:21")));
+    }
+
+    #[test]
+    fn test_lists_restrict_gateways_and_tags() {
+        let allowlist = Allowlist::new(
+            Some(vec![mac("AA:BB:CC:DD:EE:FF")]),
+            Some(vec![mac("DD:19:92:CB:60:21"), mac("E1:67:4C:F5:77:29")]),
+        );
+        assert!(allowlist.allows_gateway(mac("AA:BB:CC:DD:EE:FF")));
+        assert!(!allowlist.allows_gateway(mac("11:22:33:44:55:66")));
+        assert!(allowlist.allows_tag(mac("DD:19:92:CB:60:21")));
+        assert!(!allowlist.allows_tag(mac("DE:4F:BC:29:EC:B5")));
+        assert!(!allowlist.allows_tag(mac("F4:01:BA:59:7B:9F")));
+
+        let mut metrics = MetricsWriter::new();
+        allowlist.collect_metrics(&mut metrics);
+        let output = metrics.render(metrics::Format::Prometheus);
+        assert!(output.contains(
+            "ruuvi_exporter_ingest_dropped_messages_total{reason=\"unknown_gateway\"} 1\n"
+        ));
+        assert!(
+            output.contains("ruuvi_exporter_ingest_dropped_tags_total{reason=\"unknown_tag\"} 2\n")
+        );
+    }
+}
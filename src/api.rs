@@ -0,0 +1,361 @@
+use hifitime::Epoch;
+use ruuvi_decoders::RuuviData;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::collector::CollectorOptions;
+use crate::config::MacMapping;
+use crate::mac::MacAddress;
+use crate::measurements::{Measurements, Tag, TagStatus};
+use crate::stats::data_format_name;
+
+/// A decoded value and its unit.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Reading {
+    pub value: f64,
+    /// Unit symbol, omitted for counters and indices
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit: Option<&'static str>,
+}
+
+/// Observation of the tag by a single gateway.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GatewayReading {
+    pub gw_mac: MacAddress,
+    pub rssi: i32,
+    /// Time the gateway last heard the tag, in Unix seconds
+    pub last_seen: f64,
+}
+
+/// Current state of a tag as returned by the tag API.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TagReport {
+    pub mac: MacAddress,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Labels from the mapping file besides the name
+    pub labels: BTreeMap<String, String>,
+    pub data_format: &'static str,
+    /// Time of the current reading, in Unix seconds
+    pub last_seen: f64,
+    /// Time the exporter received the current reading, in Unix seconds
+    pub last_received: f64,
+    /// Whether the tag has been heard within its timeout
+    pub up: bool,
+    /// Signal strength of the current reading
+    pub rssi: i32,
+    /// Gateway that delivered the current reading
+    pub best_gateway: MacAddress,
+    pub gateways: Vec<GatewayReading>,
+    /// Calibrated values keyed by quantity. Empty while the tag is down.
+    pub values: BTreeMap<&'static str, Reading>,
+}
+
+/// Body of the tag list endpoint.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TagList {
+    /// Tags sorted by MAC address
+    pub tags: Vec<TagReport>,
+}
+
+/// Every quantity `readings` can report.
+pub const FIELDS: &[&str] = &[
+    "temperature",
+    "humidity",
+    "pressure",
+    "acceleration_x",
+    "acceleration_y",
+    "acceleration_z",
+    "battery_voltage",
+    "tx_power",
+    "movement_counter",
+    "measurement_sequence",
+    "pm1_0",
+    "pm2_5",
+    "pm4_0",
+    "pm10_0",
+    "co2",
+    "voc_index",
+    "nox_index",
+    "luminosity",
+];
+
+/// Collects the decoded values of a reading, in the units the tags measure them in.
+pub fn readings(values: &RuuviData) -> BTreeMap<&'static str, Reading> {
+    let mut readings = BTreeMap::new();
+    let mut add = |key, value: Option<f64>, unit| {
+        if let Some(value) = value {
+            readings.insert(key, Reading { value, unit });
+        }
+    };
+
+    match values {
+        RuuviData::V5(data) => {
+            add("temperature", data.temperature, Some("°C"));
+            add("humidity", data.humidity, Some("%"));
+            add("pressure", data.pressure, Some("Pa"));
+            add(
+                "acceleration_x",
+                data.acceleration_x.map(|a| f64::from(a) / 1000.0),
+                Some("g"),
+            );
+            add(
+                "acceleration_y",
+                data.acceleration_y.map(|a| f64::from(a) / 1000.0),
+                Some("g"),
+            );
+            add(
+                "acceleration_z",
+                data.acceleration_z.map(|a| f64::from(a) / 1000.0),
+                Some("g"),
+            );
+            add(
+                "battery_voltage",
+                data.battery_voltage.map(|v| f64::from(v) / 1000.0),
+                Some("V"),
+            );
+            add("tx_power", data.tx_power.map(f64::from), Some("dBm"));
+            add(
+                "movement_counter",
+                data.movement_counter.map(f64::from),
+                None,
+            );
+            add(
+                "measurement_sequence",
+                data.measurement_sequence.map(f64::from),
+                None,
+            );
+        }
+        RuuviData::V6(data) => {
+            add("temperature", data.temperature, Some("°C"));
+            add("humidity", data.humidity, Some("%"));
+            add("pressure", data.pressure.map(|p| p * 100.0), Some("Pa"));
+            add("pm2_5", data.pm2_5, Some("µg/m³"));
+            add("co2", data.co2.map(f64::from), Some("ppm"));
+            add("voc_index", data.voc_index.map(f64::from), None);
+            add("nox_index", data.nox_index.map(f64::from), None);
+            add("luminosity", data.luminosity, Some("lx"));
+            add(
+                "measurement_sequence",
+                data.measurement_sequence.map(f64::from),
+                None,
+            );
+        }
+        RuuviData::E1(data) => {
+            add("temperature", data.temperature, Some("°C"));
+            add("humidity", data.humidity, Some("%"));
+            add("pressure", data.pressure.map(|p| p * 100.0), Some("Pa"));
+            add("pm1_0", data.pm1_0, Some("µg/m³"));
+            add("pm2_5", data.pm2_5, Some("µg/m³"));
+            add("pm4_0", data.pm4_0, Some("µg/m³"));
+            add("pm10_0", data.pm10_0, Some("µg/m³"));
+            add("co2", data.co2.map(f64::from), Some("ppm"));
+            add("voc_index", data.voc_index.map(f64::from), None);
+            add("nox_index", data.nox_index.map(f64::from), None);
+            add("luminosity", data.luminosity, Some("lx"));
+            add(
+                "measurement_sequence",
+                data.measurement_sequence.map(f64::from),
+                None,
+            );
+        }
+    }
+    readings
+}
+
+fn tag_report(mac: MacAddress, tag: &Tag, names: &MacMapping, status: TagStatus) -> TagReport {
+    let up = status == TagStatus::Up;
+    TagReport {
+        mac,
+        name: names.lookup(&mac).map(str::to_string),
+        labels: names
+            .extra_labels(&mac)
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect(),
+        data_format: data_format_name(&tag.values),
+        last_seen: tag.last_seen.to_unix_seconds(),
+        last_received: tag.last_received.to_unix_seconds(),
+        up,
+        rssi: tag.rssi(),
+        best_gateway: tag.best_gateway,
+        gateways: tag
+            .heard_by
+            .iter()
+            .map(|(gw_mac, hearing)| GatewayReading {
+                gw_mac: *gw_mac,
+                rssi: hearing.rssi,
+                last_seen: hearing.last_seen.to_unix_seconds(),
+            })
+            .collect(),
+        // Like the metrics, stale values of a tag that is down are not reported
+        values: if up {
+            readings(&names.calibration(&mac).apply(&tag.values))
+        } else {
+            BTreeMap::new()
+        },
+    }
+}
+
+/// Reports the tag `mac`, unless it is unknown or has expired.
+pub fn tag(
+    state: &Measurements,
+    names: &MacMapping,
+    options: &CollectorOptions,
+    now: Epoch,
+    mac: MacAddress,
+) -> Option<TagReport> {
+    let tag = state.tags.get(&mac)?;
+    let status = tag.status(now, names.tag_timeout(&mac, options.tag_timeout));
+    (status != TagStatus::Expired).then(|| tag_report(mac, tag, names, status))
+}
+
+/// Reports every tag that has not expired.
+pub fn tags(
+    state: &Measurements,
+    names: &MacMapping,
+    options: &CollectorOptions,
+    now: Epoch,
+) -> TagList {
+    let mut macs: Vec<_> = state.tags.keys().copied().collect();
+    macs.sort();
+    TagList {
+        tags: macs
+            .into_iter()
+            .filter_map(|mac| tag(state, names, options, now, mac))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::seconds;
+    use crate::rw_message::TagMessage;
+
+    fn mac(s: &str) -> MacAddress {
+        s.parse().unwrap()
+    }
+
+    fn measurements() -> Measurements {
+        let mut measurements = Measurements::new();
+        for (tag_mac, data) in [
+            (
+                "DD:19:92:CB:60:21",
+                "0201061BFF9904050FE0337CC4ABFC1400340024A5B6EBA544DD1992CB6021",
+            ),
+            (
+                "E1:67:4C:F5:77:29",
+                "2BFF9904E110FE408CC53D000300060009000B02560D00FFFFFFFFFFFF001EBEB8FFFFFFFFFFF6BFB2EED156",
+            ),
+        ] {
+            measurements.update_tag(
+                mac("AA:AA:AA:AA:AA:AA"),
+                &TagMessage {
+                    mac: mac(tag_mac),
+                    data: hex::decode(data).unwrap(),
+                    timestamp: Epoch::from_unix_seconds(1000.0),
+                    rssi: -60,
+                },
+                Epoch::from_unix_seconds(1000.0),
+            );
+        }
+        measurements
+    }
+
+    #[test]
+    fn test_tag_report() {
+        let names: MacMapping = serde_yaml::from_str(
+            r#"
+            "DD:19:92:CB:60:21":
+                name: "Living Room"
+                floor: 2
+            "#,
+        )
+        .unwrap();
+        let now = Epoch::from_unix_seconds(1010.0);
+        let report = tag(
+            &measurements(),
+            &names,
+            &CollectorOptions::default(),
+            now,
+            mac("DD:19:92:CB:60:21"),
+        )
+        .unwrap();
+
+        assert_eq!(report.name.as_deref(), Some("Living Room"));
+        assert_eq!(report.labels["floor"], "2");
+        assert_eq!(report.data_format, "5");
+        assert_eq!(report.last_seen, 1000.0);
+        assert_eq!(report.last_received, 1000.0);
+        assert!(report.up);
+        assert_eq!(report.rssi, -60);
+        assert_eq!(
+            report.values["temperature"],
+            Reading {
+                value: 20.32,
+                unit: Some("°C")
+            }
+        );
+        assert_eq!(report.values["battery_voltage"].unit, Some("V"));
+        assert_eq!(report.values["movement_counter"].unit, None);
+
+        for tag in measurements().tags.values() {
+            assert!(readings(&tag.values).keys().all(|key| FIELDS.contains(key)));
+        }
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["mac"], "DD:19:92:CB:60:21");
+        assert_eq!(json["values"]["humidity"]["unit"], "%");
+        assert_eq!(json["gateways"][0]["gw_mac"], "AA:AA:AA:AA:AA:AA");
+
+        // Values are reported calibrated
+        let names: MacMapping = serde_yaml::from_str(
+            r#"
+            "DD:19:92:CB:60:21":
+                calibration:
+                    temperature_offset: -0.5
+            "#,
+        )
+        .unwrap();
+        let report = tag(
+            &measurements(),
+            &names,
+            &CollectorOptions::default(),
+            now,
+            mac("DD:19:92:CB:60:21"),
+        )
+        .unwrap();
+        assert!((report.values["temperature"].value - 19.82).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_tag_list() {
+        let state = measurements();
+        let names = MacMapping::default();
+        let options = CollectorOptions {
+            tag_timeout: Some(seconds(60)),
+            ..CollectorOptions::default()
+        };
+
+        let list = tags(&state, &names, &options, Epoch::from_unix_seconds(1010.0));
+        let macs: Vec<_> = list.tags.iter().map(|tag| tag.mac).collect();
+        assert_eq!(macs, [mac("DD:19:92:CB:60:21"), mac("E1:67:4C:F5:77:29")]);
+        assert_eq!(list.tags[1].data_format, "E1");
+        assert!(list.tags[1].values.contains_key("pm10_0"));
+
+        // Down tags are listed without values, expired tags are left out
+        let list = tags(&state, &names, &options, Epoch::from_unix_seconds(1100.0));
+        assert!(!list.tags[0].up);
+        assert!(list.tags[0].values.is_empty());
+        let list = tags(&state, &names, &options, Epoch::from_unix_seconds(1200.0));
+        assert!(list.tags.is_empty());
+        assert!(tag(
+            &state,
+            &names,
+            &options,
+            Epoch::from_unix_seconds(1010.0),
+            mac("00:00:00:00:00:00")
+        )
+        .is_none());
+    }
+}
@@ -0,0 +1,316 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::mac::MacAddress;
+use crate::metrics::{self, metric, MetricsWriter};
+
+/// Credentials a client can send in its `Authorization` header.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum Credential {
+    /// `Authorization: Bearer <token>`
+    Bearer(String),
+    /// `Authorization: Basic <base64 of username:password>`
+    Basic { username: String, password: String },
+}
+
+impl Credential {
+    /// Checks the value of an `Authorization` header against the credential.
+    fn matches(&self, authorization: &str) -> bool {
+        let Some((scheme, value)) = authorization.trim().split_once(' ') else {
+            return false;
+        };
+        let value = value.trim();
+        match self {
+            Credential::Bearer(token) => {
+                scheme.eq_ignore_ascii_case("bearer") && constant_time_eq(token, value)
+            }
+            Credential::Basic { username, password } => {
+                scheme.eq_ignore_ascii_case("basic")
+                    && STANDARD.decode(value).is_ok_and(|decoded| {
+                        constant_time_eq(format!("{username}:{password}"), decoded)
+                    })
+            }
+        }
+    }
+}
+
+/// Compares secrets in time that does not depend on where they differ.
+fn constant_time_eq(a: impl AsRef<[u8]>, b: impl AsRef<[u8]>) -> bool {
+    let (a, b) = (a.as_ref(), b.as_ref());
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Checks an `Authorization` header against a set of credentials.
+fn accepts<'a>(
+    credentials: impl IntoIterator<Item = &'a Credential>,
+    authorization: Option<&str>,
+) -> bool {
+    authorization.is_some_and(|authorization| {
+        credentials
+            .into_iter()
+            .any(|credential| credential.matches(authorization))
+    })
+}
+
+/// Value of the `WWW-Authenticate` header sent with rejections, asking for basic
+/// authentication if any of `credentials` is a username and password.
+fn challenge<'a>(credentials: impl IntoIterator<Item = &'a Credential>) -> &'static str {
+    let basic = credentials
+        .into_iter()
+        .any(|credential| matches!(credential, Credential::Basic { .. }));
+    if basic {
+        r#"Basic realm="ruuvi-gw-exporter""#
+    } else {
+        "Bearer"
+    }
+}
+
+/// `ingest_auth` section of the config file.
+///
+/// If no credentials are configured, the ingest endpoint accepts all requests.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IngestAuthConfig {
+    /// Credentials accepted from any gateway
+    #[serde(default)]
+    pub tokens: Vec<Credential>,
+    /// Credentials accepted only from the gateway with the given MAC address
+    #[serde(default)]
+    pub gateways: HashMap<MacAddress, Credential>,
+}
+
+/// Authenticates the gateways posting to the ingest endpoint.
+#[derive(Debug, Default)]
+pub struct IngestAuth {
+    config: IngestAuthConfig,
+    rejected: AtomicU64,
+}
+
+impl IngestAuth {
+    pub fn new(config: IngestAuthConfig) -> Self {
+        Self {
+            config,
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        !self.config.tokens.is_empty() || !self.config.gateways.is_empty()
+    }
+
+    fn credentials(&self) -> impl Iterator<Item = &Credential> {
+        self.config
+            .tokens
+            .iter()
+            .chain(self.config.gateways.values())
+    }
+
+    /// Checks whether the `Authorization` header matches any configured credential, before
+    /// the gateway sending the request is known. Rejected requests are counted.
+    pub fn precheck(&self, authorization: Option<&str>) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+        let allowed = accepts(self.credentials(), authorization);
+        if !allowed {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        allowed
+    }
+
+    /// Checks whether a request from the gateway `gw_mac` with the given `Authorization` header
+    /// is allowed. Rejected requests are counted.
+    pub fn authorize(&self, authorization: Option<&str>, gw_mac: MacAddress) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+        let credentials = self.config.tokens.iter();
+        let allowed = accepts(
+            credentials.chain(self.config.gateways.get(&gw_mac)),
+            authorization,
+        );
+        if !allowed {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        allowed
+    }
+
+    /// Value of the `WWW-Authenticate` header sent with rejections.
+    pub fn challenge(&self) -> &'static str {
+        challenge(self.credentials())
+    }
+
+    pub fn collect_metrics(&self, metrics: &mut MetricsWriter) {
+        metrics.push(
+            &metrics::INGEST_UNAUTHORIZED_REQUESTS,
+            &metric(metrics::INGEST_UNAUTHORIZED_REQUESTS.name)
+                .value(self.rejected.load(Ordering::Relaxed)),
+        );
+    }
+}
+
+/// `metrics_auth` section of the config file.
+///
+/// Also protects the tag API. If no credentials are configured, anyone can scrape the metrics.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsAuthConfig {
+    #[serde(default)]
+    pub tokens: Vec<Credential>,
+}
+
+/// Authenticates scrapes of the metrics endpoint.
+#[derive(Debug, Default)]
+pub struct MetricsAuth {
+    config: MetricsAuthConfig,
+    rejected: AtomicU64,
+}
+
+impl MetricsAuth {
+    pub fn new(config: MetricsAuthConfig) -> Self {
+        Self {
+            config,
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Checks whether a scrape with the given `Authorization` header is allowed. Rejected
+    /// scrapes are counted.
+    pub fn authorize(&self, authorization: Option<&str>) -> bool {
+        if self.config.tokens.is_empty() {
+            return true;
+        }
+        let allowed = accepts(&self.config.tokens, authorization);
+        if !allowed {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        allowed
+    }
+
+    /// Value of the `WWW-Authenticate` header sent with rejections, asking for the kind of
+    /// credentials that are configured.
+    pub fn challenge(&self) -> &'static str {
+        challenge(&self.config.tokens)
+    }
+
+    pub fn collect_metrics(&self, metrics: &mut MetricsWriter) {
+        metrics.push(
+            &metrics::METRICS_UNAUTHORIZED_REQUESTS,
+            &metric(metrics::METRICS_UNAUTHORIZED_REQUESTS.name)
+                .value(self.rejected.load(Ordering::Relaxed)),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mac(s: &str) -> MacAddress {
+        s.parse().unwrap()
+    }
+
+    fn auth(yaml: &str) -> IngestAuth {
+        IngestAuth::new(serde_yaml::from_str(yaml).unwrap())
+    }
+
+    #[test]
+    fn test_disabled_auth_accepts_everything() {
+        let auth = IngestAuth::default();
+        assert!(auth.authorize(None, mac("AA:BB:CC:DD:EE:FF")));
+        assert!(auth.authorize(Some("Bearer anything"), mac("AA:BB:CC:DD:EE:FF")));
+        assert!(auth.precheck(None));
+        assert_eq!(auth.challenge(), "Bearer");
+    }
+
+    #[test]
+    fn test_shared_credentials() {
+        let auth = auth(
+            r#"
+            tokens:
+                - "s3cret"
+                - username: "ruuvi"
+                  password: "hunter2"
+            "#,
+        );
+        let gw = mac("AA:BB:CC:DD:EE:FF");
+        assert!(auth.authorize(Some("Bearer s3cret"), gw));
+        assert!(auth.authorize(Some("bearer  s3cret "), gw));
+        // ruuvi:hunter2
+        assert!(auth.authorize(Some("Basic cnV1dmk6aHVudGVyMg=="), gw));
+
+        assert!(!auth.authorize(None, gw));
+        assert!(!auth.authorize(Some("Bearer s3cre"), gw));
+        assert!(!auth.authorize(Some("Basic s3cret"), gw));
+        assert!(!auth.authorize(Some("s3cret"), gw));
+        // ruuvi:hunter3
+        assert!(!auth.authorize(Some("Basic cnV1dmk6aHVudGVyMw=="), gw));
+        assert_eq!(auth.rejected.load(Ordering::Relaxed), 5);
+    }
+
+    #[test]
+    fn test_gateway_credentials() {
+        let auth = auth(
+            r#"
+            gateways:
+                "aa:bb:cc:dd:ee:ff": "kitchen-token"
+                "11:22:33:44:55:66":
+                    username: "garage"
+                    password: "door"
+            "#,
+        );
+        assert!(auth.authorize(Some("Bearer kitchen-token"), mac("AA:BB:CC:DD:EE:FF")));
+        // garage:door
+        assert!(auth.authorize(Some("Basic Z2FyYWdlOmRvb3I="), mac("11:22:33:44:55:66")));
+        // Gateway tokens are not valid for other gateways
+        assert!(!auth.authorize(Some("Bearer kitchen-token"), mac("11:22:33:44:55:66")));
+        assert!(!auth.authorize(Some("Bearer kitchen-token"), mac("00:00:00:00:00:00")));
+
+        // Any gateway's credential passes the check made before the gateway is known
+        assert!(auth.precheck(Some("Bearer kitchen-token")));
+        assert!(!auth.precheck(Some("Bearer junk")));
+        assert!(!auth.precheck(None));
+        assert_eq!(auth.challenge(), r#"Basic realm="ruuvi-gw-exporter""#);
+
+        let mut metrics = MetricsWriter::new();
+        auth.collect_metrics(&mut metrics);
+        assert!(metrics
+            .render(metrics::Format::Prometheus)
+            .contains("ruuvi_exporter_ingest_unauthorized_requests_total 4\n"));
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(serde_yaml::from_str::<IngestAuthConfig>("tokens: [{username: x}]").is_err());
+        assert!(serde_yaml::from_str::<IngestAuthConfig>("gateways: {gateway: x}").is_err());
+        assert!(serde_yaml::from_str::<IngestAuthConfig>("token: [x]").is_err());
+    }
+
+    #[test]
+    fn test_metrics_auth() {
+        let auth = MetricsAuth::new(
+            serde_yaml::from_str(r#"tokens: [{username: "prometheus", password: "scrape"}]"#)
+                .unwrap(),
+        );
+        // prometheus:scrape
+        assert!(auth.authorize(Some("Basic cHJvbWV0aGV1czpzY3JhcGU=")));
+        assert!(!auth.authorize(Some("Bearer scrape")));
+        assert!(!auth.authorize(None));
+        assert_eq!(auth.challenge(), r#"Basic realm="ruuvi-gw-exporter""#);
+
+        let mut metrics = MetricsWriter::new();
+        auth.collect_metrics(&mut metrics);
+        assert!(metrics
+            .render(metrics::Format::Prometheus)
+            .contains("ruuvi_exporter_metrics_unauthorized_requests_total 2\n"));
+
+        let open = MetricsAuth::default();
+        assert!(open.authorize(None));
+        assert_eq!(open.challenge(), "Bearer");
+    }
+}
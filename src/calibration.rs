@@ -0,0 +1,190 @@
+use ruuvi_decoders::RuuviData;
+use serde::Deserialize;
+use std::borrow::Cow;
+
+/// Corrections of a tag's readings from the mapping file, applied before the readings are
+/// exported. Quantities without a correction are exported as measured.
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Calibration {
+    /// Added to the temperature, in °C
+    pub temperature_offset: Option<f64>,
+    /// Added to the relative humidity after scaling, in percentage points
+    pub humidity_offset: Option<f64>,
+    /// Multiplies the relative humidity
+    pub humidity_scale: Option<f64>,
+    /// Added to the air pressure, in Pa
+    pub pressure_offset: Option<f64>,
+    /// Added to the CO2 concentration, in ppm
+    pub co2_offset: Option<i32>,
+}
+
+impl Calibration {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn calibrates_temperature(&self) -> bool {
+        self.temperature_offset.is_some()
+    }
+
+    pub fn calibrates_humidity(&self) -> bool {
+        self.humidity_offset.is_some() || self.humidity_scale.is_some()
+    }
+
+    pub fn calibrates_pressure(&self) -> bool {
+        self.pressure_offset.is_some()
+    }
+
+    pub fn calibrates_co2(&self) -> bool {
+        self.co2_offset.is_some()
+    }
+
+    /// Returns the name of the first setting that would produce nonsensical readings.
+    pub fn invalid_field(&self) -> Option<&'static str> {
+        let offsets = [
+            ("temperature_offset", self.temperature_offset),
+            ("humidity_offset", self.humidity_offset),
+            ("pressure_offset", self.pressure_offset),
+        ];
+        if let Some((field, _)) = offsets
+            .into_iter()
+            .find(|(_, offset)| offset.is_some_and(|offset| !offset.is_finite()))
+        {
+            return Some(field);
+        }
+        self.humidity_scale
+            .is_some_and(|scale| !(scale.is_finite() && scale > 0.0))
+            .then_some("humidity_scale")
+    }
+
+    fn temperature(&self, temperature: Option<f64>) -> Option<f64> {
+        temperature.map(|t| t + self.temperature_offset.unwrap_or(0.0))
+    }
+
+    /// Calibrated relative humidity in percent, kept within 0-100 %.
+    fn humidity(&self, humidity: Option<f64>) -> Option<f64> {
+        humidity.map(|h| {
+            let h = h * self.humidity_scale.unwrap_or(1.0) + self.humidity_offset.unwrap_or(0.0);
+            h.clamp(0.0, 100.0)
+        })
+    }
+
+    /// Calibrated pressure. `unit` is the size of the pressure unit in Pa.
+    fn pressure(&self, pressure: Option<f64>, unit: f64) -> Option<f64> {
+        pressure.map(|p| p + self.pressure_offset.unwrap_or(0.0) / unit)
+    }
+
+    fn co2(&self, co2: Option<u16>) -> Option<u16> {
+        co2.map(|co2| {
+            let co2 = i32::from(co2) + self.co2_offset.unwrap_or(0);
+            u16::try_from(co2.max(0)).unwrap_or(u16::MAX)
+        })
+    }
+
+    /// Returns `values` with the corrections applied.
+    pub fn apply<'a>(&self, values: &'a RuuviData) -> Cow<'a, RuuviData> {
+        if self.is_empty() {
+            return Cow::Borrowed(values);
+        }
+        let mut values = values.clone();
+        match &mut values {
+            RuuviData::V5(data) => {
+                data.temperature = self.temperature(data.temperature);
+                data.humidity = self.humidity(data.humidity);
+                data.pressure = self.pressure(data.pressure, 1.0);
+            }
+            // Formats 6 and E1 decode the pressure in hPa
+            RuuviData::V6(data) => {
+                data.temperature = self.temperature(data.temperature);
+                data.humidity = self.humidity(data.humidity);
+                data.pressure = self.pressure(data.pressure, 100.0);
+                data.co2 = self.co2(data.co2);
+            }
+            RuuviData::E1(data) => {
+                data.temperature = self.temperature(data.temperature);
+                data.humidity = self.humidity(data.humidity);
+                data.pressure = self.pressure(data.pressure, 100.0);
+                data.co2 = self.co2(data.co2);
+            }
+        }
+        Cow::Owned(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ruuvi_decoders::decode;
+
+    fn assert_close(actual: Option<f64>, expected: f64) {
+        let actual = actual.unwrap();
+        assert!((actual - expected).abs() < 1e-9, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn test_apply() {
+        let calibration: Calibration = serde_yaml::from_str(
+            "{temperature_offset: -0.5, humidity_scale: 1.1, humidity_offset: 2, \
+             pressure_offset: 150, co2_offset: -1000}",
+        )
+        .unwrap();
+
+        let v5 = decode("050FE0337CC4ABFC1400340024A5B6EBA544DD1992CB6021").unwrap();
+        let RuuviData::V5(calibrated) = calibration.apply(&v5).into_owned() else {
+            panic!("Expected format 5");
+        };
+        assert_close(calibrated.temperature, 19.82);
+        assert_close(calibrated.humidity, 32.95 * 1.1 + 2.0);
+        assert_close(calibrated.pressure, 100_347.0 + 150.0);
+
+        let e1 = decode(
+            "E1170C5668C79E0065007004BD11CA00C90A0213E0ACFFFFFFDECDEE10FFFFFFFFFFCBB8334C884F",
+        )
+        .unwrap();
+        let (RuuviData::E1(raw), RuuviData::E1(calibrated)) =
+            (&e1, calibration.apply(&e1).into_owned())
+        else {
+            panic!("Expected format E1");
+        };
+        assert_close(calibrated.pressure, raw.pressure.unwrap() + 1.5);
+        // Clamped to the valid range
+        assert_eq!(calibrated.co2, Some(0));
+        assert_eq!(calibrated.pm2_5, raw.pm2_5);
+
+        assert!(matches!(
+            Calibration::default().apply(&e1),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn test_humidity_limits() {
+        let calibration = Calibration {
+            humidity_offset: Some(5.0),
+            ..Calibration::default()
+        };
+        assert_eq!(calibration.humidity(Some(97.0)), Some(100.0));
+        let calibration = Calibration {
+            humidity_offset: Some(-5.0),
+            ..Calibration::default()
+        };
+        assert_eq!(calibration.humidity(Some(3.0)), Some(0.0));
+    }
+
+    #[test]
+    fn test_invalid_field() {
+        assert_eq!(Calibration::default().invalid_field(), None);
+        let calibration = Calibration {
+            humidity_scale: Some(0.0),
+            ..Calibration::default()
+        };
+        assert_eq!(calibration.invalid_field(), Some("humidity_scale"));
+        let calibration = Calibration {
+            temperature_offset: Some(f64::NAN),
+            ..Calibration::default()
+        };
+        assert_eq!(calibration.invalid_field(), Some("temperature_offset"));
+        assert!(serde_yaml::from_str::<Calibration>("{temperature: 1}").is_err());
+    }
+}
@@ -0,0 +1,407 @@
+use serde_yaml::{Mapping, Value};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::config::{Cli, Config, FileConfig, MappingError, TagEntry, ENV_PREFIX};
+use crate::mac::MacAddress;
+use crate::tls::CertStore;
+
+/// A problem found in the configuration, with the place it was found at if known.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub path: Option<PathBuf>,
+    /// 1-based line number in `path`
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl Problem {
+    fn new(path: Option<&Path>, line: Option<usize>, message: impl ToString) -> Self {
+        Self {
+            path: path.map(Path::to_path_buf),
+            line,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.path, self.line) {
+            (Some(path), Some(line)) => write!(f, "{}:{line}: {}", path.display(), self.message),
+            (Some(path), None) => write!(f, "{}: {}", path.display(), self.message),
+            (None, _) => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Runs the `check-config` subcommand and returns the exit code of the process.
+pub fn run(cli: Cli) -> i32 {
+    let problems = check_config(cli, |key| std::env::var(key).ok());
+    if problems.is_empty() {
+        println!("Configuration is valid");
+        return 0;
+    }
+    for problem in &problems {
+        eprintln!("{problem}");
+    }
+    eprintln!("Found {} problem(s) in the configuration", problems.len());
+    1
+}
+
+/// Loads the configuration like the exporter does, but collects every problem instead of
+/// stopping at the first one.
+pub fn check_config(mut cli: Cli, env: impl Fn(&str) -> Option<String>) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    let config_var = format!("{ENV_PREFIX}CONFIG");
+    let config_path = cli
+        .config
+        .clone()
+        .or_else(|| env(&config_var).map(PathBuf::from));
+    let file_is_valid = match &config_path {
+        Some(path) => {
+            let file_problems = check_config_file(path);
+            let valid = file_problems.is_empty();
+            problems.extend(file_problems);
+            valid
+        }
+        None => true,
+    };
+
+    // The problems of an invalid config file are already reported, so leave it out to find
+    // problems in the other sources
+    if !file_is_valid {
+        cli.config = None;
+    }
+    let env = |key: &str| {
+        if !file_is_valid && key == config_var {
+            None
+        } else {
+            env(key)
+        }
+    };
+    match Config::resolve(cli, env) {
+        Ok(config) => {
+            if let Some(path) = &config.mac_mapping {
+                problems.extend(check_mapping_file(path));
+            }
+            if let Some(tls) = config.tls {
+                if let Err(err) = CertStore::load(tls) {
+                    problems.push(Problem::new(None, None, err));
+                }
+            }
+        }
+        Err(err) => problems.push(Problem::new(None, None, err)),
+    }
+
+    problems
+}
+
+/// Checks every setting of the `--config` file separately.
+fn check_config_file(path: &Path) -> Vec<Problem> {
+    let (source, root) = match read_yaml(path) {
+        Ok(yaml) => yaml,
+        Err(problem) => return vec![problem],
+    };
+
+    let mut problems = Vec::new();
+    let mut has_mapping = false;
+    for (key, value) in root {
+        let Some(key) = key.as_str() else {
+            problems.push(Problem::new(Some(path), None, "Settings must be strings"));
+            continue;
+        };
+        let line = find_key_line(&source, key, 0);
+        if key == "mac_mapping" || key == "tags" {
+            if has_mapping {
+                problems.push(Problem::new(
+                    Some(path),
+                    line,
+                    "Only one of mac_mapping and tags can be set",
+                ));
+            }
+            has_mapping = true;
+        }
+        if key == "tags" {
+            problems.extend(check_mapping(path, &source, value, line.unwrap_or(1)));
+            continue;
+        }
+        let setting = Mapping::from_iter([(Value::from(key), value)]);
+        if let Err(err) = serde_yaml::from_value::<FileConfig>(Value::Mapping(setting)) {
+            problems.push(Problem::new(Some(path), line, format!("{key}: {err}")));
+        }
+    }
+    problems
+}
+
+fn check_mapping_file(path: &Path) -> Vec<Problem> {
+    match read_yaml(path) {
+        Ok((source, root)) => check_mapping(path, &source, Value::Mapping(root), 1),
+        Err(problem) => vec![problem],
+    }
+}
+
+/// Checks every entry of a tag mapping that starts on line `start` of `source`.
+fn check_mapping(path: &Path, source: &str, mapping: Value, start: usize) -> Vec<Problem> {
+    let mapping = match mapping {
+        Value::Mapping(mapping) => mapping,
+        Value::Null => return vec![],
+        _ => {
+            return vec![Problem::new(
+                Some(path),
+                Some(start),
+                "Expected a map from MAC addresses to tags",
+            )]
+        }
+    };
+
+    let mut problems = Vec::new();
+    let mut spellings: HashMap<MacAddress, String> = HashMap::new();
+    for (key, value) in mapping {
+        let key = match key {
+            Value::String(key) => key,
+            other => serde_yaml::to_string(&other)
+                .unwrap_or_default()
+                .trim()
+                .to_string(),
+        };
+        let line = find_key_line(source, &key, start - 1);
+
+        let Ok(mac) = key.parse::<MacAddress>() else {
+            let err = MappingError::InvalidMac(key);
+            problems.push(Problem::new(Some(path), line, err));
+            continue;
+        };
+        if let Some(first) = spellings.insert(mac, key.clone()) {
+            let err = MappingError::DuplicateMac { first, second: key };
+            problems.push(Problem::new(Some(path), line, err));
+        }
+        let entry = match TagEntry::from_value(value) {
+            Ok(entry) => entry,
+            Err(err) => {
+                let message = format!("Tag {mac}: {err}");
+                problems.push(Problem::new(Some(path), line, message));
+                continue;
+            }
+        };
+        for err in entry.problems(mac) {
+            // Point at the offending key within the entry
+            let key = match &err {
+                MappingError::InvalidLabelName { label, .. }
+                | MappingError::ReservedLabel { label, .. } => label.as_str(),
+                MappingError::InvalidCalibration { field, .. } => field,
+                _ => "",
+            };
+            let key_line = line.and_then(|line| find_key_line(source, key, line));
+            problems.push(Problem::new(Some(path), key_line.or(line), err));
+        }
+    }
+    problems
+}
+
+/// Reads and parses a YAML file whose top level is a map.
+fn read_yaml(path: &Path) -> Result<(String, Mapping), Problem> {
+    let source = fs::read_to_string(path)
+        .map_err(|err| Problem::new(Some(path), None, format!("Failed to read: {err}")))?;
+    let root = match serde_yaml::from_str::<Value>(&source) {
+        Ok(Value::Mapping(root)) => root,
+        Ok(Value::Null) => Mapping::new(),
+        Ok(_) => return Err(Problem::new(Some(path), Some(1), "Expected a map")),
+        Err(err) => {
+            let line = err.location().map(|location| location.line());
+            return Err(Problem::new(Some(path), line, err));
+        }
+    };
+    Ok((source, root))
+}
+
+/// Finds the 1-based number of the line defining `key`, searching after line `after`.
+///
+/// Only keys at the start of a line, or opening a flow style map, are found. No line is
+/// reported rather than a line merely mentioning `key`.
+fn find_key_line(source: &str, key: &str, after: usize) -> Option<usize> {
+    let quoted = [
+        format!("{key}:"),
+        format!("\"{key}\":"),
+        format!("'{key}':"),
+    ];
+    source
+        .lines()
+        .enumerate()
+        .skip(after)
+        .find(|(_, line)| {
+            let line = line
+                .trim_start()
+                .trim_start_matches("- ")
+                .trim_start_matches('{')
+                .trim_start();
+            quoted.iter().any(|key| line.starts_with(key.as_str()))
+        })
+        .map(|(index, _)| index + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    fn create_temp_file(content: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        write!(file, "{}", content).unwrap();
+        file
+    }
+
+    fn check(args: &[&str], env: &[(&str, &str)]) -> Vec<(Option<usize>, String)> {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let cli = Cli::try_parse_from(
+            ["program", "check-config"]
+                .into_iter()
+                .chain(args.iter().copied()),
+        )
+        .unwrap();
+        check_config(cli, |key| env.get(key).cloned())
+            .into_iter()
+            .map(|problem| (problem.line, problem.message))
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_config() {
+        let mapping = create_temp_file(r#"{"DD:19:92:CB:60:21": "Bedroom"}"#);
+        let config = create_temp_file(&format!(
+            "port: 9100\ninterface: \"::1\"\nmac_mapping: {:?}\n",
+            mapping.path()
+        ));
+        assert_eq!(check(&["-c", config.path().to_str().unwrap()], &[]), []);
+        assert_eq!(check(&[], &[]), []);
+    }
+
+    #[test]
+    fn test_config_file_problems() {
+        let config = create_temp_file(
+            r#"port: 9100
+interface: "localhost"
+prot: 9000
+tags:
+  "dd:19:92:cb:60:21": "Bedroom"
+  "Office": "DE:4F:BC:29:EC:B5"
+  "DD1992CB6021":
+    name: "Bedroom"
+    labels:
+      room-type: "bedroom"
+      gw_mac: "AA:BB:CC:DD:EE:FF"
+  "E1:67:4C:F5:77:29":
+    nmae: "Bathroom"
+"#,
+        );
+        let problems = check(&["-c", config.path().to_str().unwrap()], &[]);
+        let lines: Vec<_> = problems.iter().map(|(line, _)| *line).collect();
+        assert_eq!(
+            lines,
+            [
+                Some(2),
+                Some(3),
+                Some(6),
+                Some(7),
+                Some(11),
+                Some(10),
+                Some(12)
+            ],
+            "{problems:#?}"
+        );
+        assert_eq!(problems[0].1, "interface: invalid IP address syntax");
+        assert!(problems[1].1.contains("unknown field `prot`"));
+        assert_eq!(problems[2].1, r#""Office" is not a valid MAC address"#);
+        assert_eq!(
+            problems[3].1,
+            r#""dd:19:92:cb:60:21" and "DD1992CB6021" are the same MAC address"#
+        );
+        assert_eq!(
+            problems[4].1,
+            r#"Tag DD:19:92:CB:60:21: label "gw_mac" is reserved by the exporter"#
+        );
+        assert_eq!(
+            problems[5].1,
+            r#"Tag DD:19:92:CB:60:21: "room-type" is not a valid label name"#
+        );
+        assert!(problems[6].1.contains("unknown field `nmae`"));
+    }
+
+    #[test]
+    fn test_mapping_file_problems() {
+        let mapping =
+            create_temp_file("\"AA:BB:CC:DD:EE:FF\": \"Kitchen\"\n\"AA:BB:CC:DD:EE\": x\n");
+        let problems = check(&["-m", mapping.path().to_str().unwrap()], &[]);
+        assert_eq!(
+            problems,
+            [(
+                Some(2),
+                r#""AA:BB:CC:DD:EE" is not a valid MAC address"#.to_string()
+            )]
+        );
+
+        let calibrated = create_temp_file(
+            "\"AA:BB:CC:DD:EE:FF\":\n  calibration:\n    temperature_offset: -0.5\n    \
+             humidity_scale: -1\n",
+        );
+        let problems = check(&["-m", calibrated.path().to_str().unwrap()], &[]);
+        assert_eq!(
+            problems,
+            [(
+                Some(4),
+                "Tag AA:BB:CC:DD:EE:FF: calibration humidity_scale is not a valid correction"
+                    .to_string()
+            )]
+        );
+
+        let invalid = create_temp_file("tags:\n  - [unclosed\n");
+        let problems = check(&["-m", invalid.path().to_str().unwrap()], &[]);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].0.is_some());
+    }
+
+    #[test]
+    fn test_problems_from_several_sources() {
+        let config = create_temp_file("prot: 9000\n");
+        let problems = check(
+            &[],
+            &[
+                ("RUUVI_EXPORTER_CONFIG", config.path().to_str().unwrap()),
+                ("RUUVI_EXPORTER_TAG_TIMEOUT", "soon"),
+            ],
+        );
+        assert_eq!(problems.len(), 2, "{problems:#?}");
+        assert_eq!(problems[0].0, Some(1));
+        assert_eq!(problems[1].0, None);
+        assert!(problems[1].1.contains("RUUVI_EXPORTER_TAG_TIMEOUT"));
+
+        let problems = check(&["-c", "/nonexistent/config.yaml"], &[]);
+        assert_eq!(problems.len(), 1);
+
+        let problems = check(&["--tls-cert", "cert.pem", "--tls-key", "key.pem"], &[]);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].1.starts_with(r#"Failed to read "cert.pem""#));
+    }
+
+    #[test]
+    fn test_find_key_line() {
+        let source = "a: 1\nb:\n  a: 2\n  \"c\": 3\n{'d': 4}\n";
+        assert_eq!(find_key_line(source, "a", 0), Some(1));
+        assert_eq!(find_key_line(source, "a", 1), Some(3));
+        assert_eq!(find_key_line(source, "c", 0), Some(4));
+        assert_eq!(find_key_line(source, "d", 0), Some(5));
+        assert_eq!(find_key_line(source, "e", 0), None);
+        // Values and keys further into a line are not mistaken for the key
+        let source = "name: port\ntags: {x: 1, port: 2}\n";
+        assert_eq!(find_key_line(source, "port", 0), None);
+    }
+}
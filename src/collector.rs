@@ -1,31 +1,47 @@
+use hifitime::{Duration, Epoch};
+use ruuvi_decoders::RuuviData;
+
+use crate::calibration::Calibration;
 use crate::config::MacMapping;
-use crate::measurements::Measurements;
-use crate::metrics::{labelset, metric, LabelSet};
+use crate::measurements::{Measurements, TagStatus};
+use crate::metrics::{self, labelset, metric, LabelSet, MetricFamily, MetricsWriter};
+use crate::psychrometrics::{self, DerivedMetric, DerivedMetrics};
 
 // Helper functions for metric collection
 fn add_metric<T: std::fmt::Display>(
-    metrics: &mut Vec<String>,
-    name: &str,
+    metrics: &mut MetricsWriter,
+    family: &'static MetricFamily,
     labels: &LabelSet,
+    timestamp: Option<Epoch>,
     value: T,
 ) {
-    metrics.push(metric(name).labels(labels).value(value).to_string());
+    metrics.push(
+        family,
+        &metric(family.name)
+            .labels(labels)
+            .value(value)
+            .timestamp(timestamp),
+    );
 }
 
 fn add_optional_metric<T: std::fmt::Display>(
-    metrics: &mut Vec<String>,
-    name: &str,
+    metrics: &mut MetricsWriter,
+    family: &'static MetricFamily,
     labels: &LabelSet,
+    timestamp: Option<Epoch>,
     value: Option<T>,
 ) {
     if let Some(v) = value {
-        add_metric(metrics, name, labels, v);
+        add_metric(metrics, family, labels, timestamp, v);
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn add_common_environmental_metrics(
-    metrics: &mut Vec<String>,
+    metrics: &mut MetricsWriter,
     labels: &LabelSet,
+    timestamp: Option<Epoch>,
+    derived: DerivedMetrics,
     measurement_sequence: Option<u32>,
     temperature: Option<f64>,
     humidity: Option<f64>,
@@ -33,90 +49,328 @@ fn add_common_environmental_metrics(
 ) {
     add_optional_metric(
         metrics,
-        "ruuvi_tag_sequence_number",
+        &metrics::TAG_SEQUENCE_NUMBER,
         labels,
+        timestamp,
         measurement_sequence,
     );
     add_optional_metric(
         metrics,
-        "ruuvi_tag_temperature_celsius",
+        &metrics::TAG_TEMPERATURE,
         labels,
+        timestamp,
         temperature,
     );
     add_optional_metric(
         metrics,
-        "ruuvi_tag_humidity_ratio",
+        &metrics::TAG_HUMIDITY,
         labels,
+        timestamp,
         humidity.map(|h| h / 100.0),
     );
-    add_optional_metric(metrics, "ruuvi_tag_pressure_pascals", labels, pressure);
+    add_optional_metric(metrics, &metrics::TAG_PRESSURE, labels, timestamp, pressure);
+
+    if let (Some(temperature), Some(humidity)) = (temperature, humidity) {
+        add_derived_metrics(
+            metrics,
+            labels,
+            timestamp,
+            derived,
+            temperature,
+            humidity,
+            pressure,
+        );
+    }
+}
+
+/// Adds the enabled psychrometric metrics. `humidity` is relative humidity in percent and
+/// `pressure` is in pascals.
+fn add_derived_metrics(
+    metrics: &mut MetricsWriter,
+    labels: &LabelSet,
+    timestamp: Option<Epoch>,
+    derived: DerivedMetrics,
+    temperature: f64,
+    humidity: f64,
+    pressure: Option<f64>,
+) {
+    if derived.is_enabled(DerivedMetric::DewPoint) {
+        add_optional_metric(
+            metrics,
+            &metrics::TAG_DEW_POINT,
+            labels,
+            timestamp,
+            psychrometrics::dew_point(temperature, humidity),
+        );
+    }
+    if derived.is_enabled(DerivedMetric::FrostPoint) {
+        add_optional_metric(
+            metrics,
+            &metrics::TAG_FROST_POINT,
+            labels,
+            timestamp,
+            psychrometrics::frost_point(temperature, humidity),
+        );
+    }
+    if derived.is_enabled(DerivedMetric::AbsoluteHumidity) {
+        add_metric(
+            metrics,
+            &metrics::TAG_ABSOLUTE_HUMIDITY,
+            labels,
+            timestamp,
+            psychrometrics::absolute_humidity(temperature, humidity),
+        );
+    }
+    if derived.is_enabled(DerivedMetric::MixingRatio) {
+        add_optional_metric(
+            metrics,
+            &metrics::TAG_MIXING_RATIO,
+            labels,
+            timestamp,
+            pressure.map(|pressure| psychrometrics::mixing_ratio(temperature, humidity, pressure)),
+        );
+    }
+    if derived.is_enabled(DerivedMetric::VapourPressureDeficit) {
+        add_metric(
+            metrics,
+            &metrics::TAG_VAPOUR_PRESSURE_DEFICIT,
+            labels,
+            timestamp,
+            psychrometrics::vapour_pressure_deficit(temperature, humidity),
+        );
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn add_air_quality_metrics(
-    metrics: &mut Vec<String>,
+    metrics: &mut MetricsWriter,
     labels: &LabelSet,
+    timestamp: Option<Epoch>,
     pm2_5: Option<f64>,
     co2: Option<u16>,
     voc_index: Option<u16>,
     nox_index: Option<u16>,
     luminosity: Option<f64>,
 ) {
-    add_optional_metric(metrics, "ruuvi_tag_pm2_5_ugm3", labels, pm2_5);
-    add_optional_metric(metrics, "ruuvi_tag_co2_ppm", labels, co2);
-    add_optional_metric(metrics, "ruuvi_tag_voc_index", labels, voc_index);
-    add_optional_metric(metrics, "ruuvi_tag_nox_index", labels, nox_index);
-    add_optional_metric(metrics, "ruuvi_tag_luminosity_lux", labels, luminosity);
+    add_optional_metric(metrics, &metrics::TAG_PM2_5, labels, timestamp, pm2_5);
+    add_optional_metric(metrics, &metrics::TAG_CO2, labels, timestamp, co2);
+    add_optional_metric(
+        metrics,
+        &metrics::TAG_VOC_INDEX,
+        labels,
+        timestamp,
+        voc_index,
+    );
+    add_optional_metric(
+        metrics,
+        &metrics::TAG_NOX_INDEX,
+        labels,
+        timestamp,
+        nox_index,
+    );
+    add_optional_metric(
+        metrics,
+        &metrics::TAG_LUMINOSITY,
+        labels,
+        timestamp,
+        luminosity,
+    );
 }
 
-#[allow(clippy::too_many_lines)]
-pub fn collect_metrics(state: &Measurements, names: &MacMapping) -> String {
-    let mut metrics = Vec::new();
-
-    // Gateway metrics with optional name
-    let mut gw_labels = labelset().label("gw_mac", &state.mac);
-    if let Some(name) = names.lookup(&state.mac) {
-        gw_labels = gw_labels.label("name", name);
+/// Adds the uncalibrated readings of the quantities `calibration` corrects.
+fn add_raw_metrics(
+    metrics: &mut MetricsWriter,
+    labels: &LabelSet,
+    timestamp: Option<Epoch>,
+    calibration: &Calibration,
+    raw: &RuuviData,
+) {
+    let (temperature, humidity, pressure, co2) = match raw {
+        RuuviData::V5(data) => (data.temperature, data.humidity, data.pressure, None),
+        RuuviData::V6(data) => (
+            data.temperature,
+            data.humidity,
+            data.pressure.map(|p| p * 100.0),
+            data.co2,
+        ),
+        RuuviData::E1(data) => (
+            data.temperature,
+            data.humidity,
+            data.pressure.map(|p| p * 100.0),
+            data.co2,
+        ),
+    };
+    if calibration.calibrates_temperature() {
+        add_optional_metric(
+            metrics,
+            &metrics::TAG_TEMPERATURE_RAW,
+            labels,
+            timestamp,
+            temperature,
+        );
     }
+    if calibration.calibrates_humidity() {
+        add_optional_metric(
+            metrics,
+            &metrics::TAG_HUMIDITY_RAW,
+            labels,
+            timestamp,
+            humidity.map(|h| h / 100.0),
+        );
+    }
+    if calibration.calibrates_pressure() {
+        add_optional_metric(
+            metrics,
+            &metrics::TAG_PRESSURE_RAW,
+            labels,
+            timestamp,
+            pressure,
+        );
+    }
+    if calibration.calibrates_co2() {
+        add_optional_metric(metrics, &metrics::TAG_CO2_RAW, labels, timestamp, co2);
+    }
+}
 
-    add_metric(
-        &mut metrics,
-        "ruuvi_gateway_update_timestamp_seconds",
-        &gw_labels,
-        state.last_update.to_unix_seconds(),
-    );
+/// Options controlling what `collect_metrics` exports.
+#[derive(Debug, Clone, Default)]
+pub struct CollectorOptions {
+    /// Default timeout after which a tag is reported as down
+    pub tag_timeout: Option<Duration>,
+    /// Attach the time a tag was heard to its samples
+    pub sample_timestamps: bool,
+    /// Psychrometric metrics computed from each tag's measurements
+    pub derived_metrics: DerivedMetrics,
+    /// Also export the uncalibrated readings of calibrated tags
+    pub raw_metrics: bool,
+}
 
-    add_optional_metric(
-        &mut metrics,
-        "ruuvi_gateway_nonce",
-        &gw_labels,
-        state.last_nonce,
-    );
+/// Writes the metrics of all gateways and tags in `state` into `metrics`.
+pub fn collect_metrics(
+    metrics: &mut MetricsWriter,
+    state: &Measurements,
+    names: &MacMapping,
+    options: &CollectorOptions,
+    now: Epoch,
+) {
+    // Iterate gateways and tags in sorted order for consistent output
+    let mut sorted_gateways: Vec<_> = state.gateways.iter().collect();
+    sorted_gateways.sort_by_key(|(gw_mac, _)| *gw_mac);
+
+    for (gw_mac, gateway) in sorted_gateways {
+        // Gateway metrics with optional name
+        let gw_mac_label = gw_mac.to_string();
+        let mut gw_labels = labelset().label("gw_mac", &gw_mac_label);
+        if let Some(name) = names.lookup(gw_mac) {
+            gw_labels = gw_labels.label("name", name);
+        }
+        for (key, value) in names.extra_labels(gw_mac) {
+            gw_labels = gw_labels.label(key, value);
+        }
 
-    // Tag metrics - iterate in sorted order for consistent output
+        add_metric(
+            metrics,
+            &metrics::GATEWAY_UPDATE_TIMESTAMP,
+            &gw_labels,
+            None,
+            gateway.last_update.to_unix_seconds(),
+        );
+
+        add_optional_metric(
+            metrics,
+            &metrics::GATEWAY_NONCE,
+            &gw_labels,
+            None,
+            gateway.last_nonce,
+        );
+    }
+
+    collect_tag_metrics(metrics, state, names, options, now);
+}
+
+#[allow(clippy::too_many_lines)]
+fn collect_tag_metrics(
+    metrics: &mut MetricsWriter,
+    state: &Measurements,
+    names: &MacMapping,
+    options: &CollectorOptions,
+    now: Epoch,
+) {
     let mut sorted_tags: Vec<_> = state.tags.iter().collect();
     sorted_tags.sort_by_key(|(mac, _)| *mac);
 
     for (mac, tag) in sorted_tags {
-        let mut labels = labelset().label("mac", mac).label("gw_mac", &state.mac);
+        let status = tag.status(now, names.tag_timeout(mac, options.tag_timeout));
+        if status == TagStatus::Expired {
+            continue;
+        }
+
+        let mac_label = mac.to_string();
+        let mut labels = labelset().label("mac", &mac_label);
 
         if let Some(name) = names.lookup(mac) {
             labels = labels.label("name", name);
         }
+        for (key, value) in names.extra_labels(mac) {
+            labels = labels.label(key, value);
+        }
 
         // Timestamps
         add_metric(
-            &mut metrics,
-            "ruuvi_tag_last_seen_timestamp_seconds",
+            metrics,
+            &metrics::TAG_LAST_SEEN_TIMESTAMP,
             &labels,
+            None,
             tag.last_seen.to_unix_seconds(),
         );
 
+        add_metric(
+            metrics,
+            &metrics::TAG_UP,
+            &labels,
+            None,
+            u8::from(status == TagStatus::Up),
+        );
+
+        // Only tags reporting sequence numbers are counted
+        if tag.packets.received > 0 {
+            add_metric(
+                metrics,
+                &metrics::TAG_PACKETS_RECEIVED,
+                &labels,
+                None,
+                tag.packets.received,
+            );
+            add_metric(
+                metrics,
+                &metrics::TAG_PACKETS_MISSED,
+                &labels,
+                None,
+                tag.packets.missed,
+            );
+        }
+
+        if status == TagStatus::Down {
+            // Only report liveness of tags that have timed out
+            continue;
+        }
+
+        let timestamp = options.sample_timestamps.then_some(tag.last_seen);
+
+        // Everything is exported calibrated, including the derived metrics
+        let calibration = names.calibration(mac);
+        if options.raw_metrics {
+            add_raw_metrics(metrics, &labels, timestamp, &calibration, &tag.values);
+        }
+
         // Extract data based on format
-        match &tag.values {
-            ruuvi_decoders::RuuviData::V5(data) => {
+        match &*calibration.apply(&tag.values) {
+            RuuviData::V5(data) => {
                 add_common_environmental_metrics(
-                    &mut metrics,
+                    metrics,
                     &labels,
+                    timestamp,
+                    options.derived_metrics,
                     data.measurement_sequence.map(u32::from),
                     data.temperature,
                     data.humidity,
@@ -125,9 +379,10 @@ pub fn collect_metrics(state: &Measurements, names: &MacMapping) -> String {
 
                 // Movement and acceleration
                 add_optional_metric(
-                    &mut metrics,
-                    "ruuvi_tag_movement_counter",
+                    metrics,
+                    &metrics::TAG_MOVEMENT_COUNTER,
                     &labels,
+                    timestamp,
                     data.movement_counter,
                 );
 
@@ -136,11 +391,16 @@ pub fn collect_metrics(state: &Measurements, names: &MacMapping) -> String {
                     data.acceleration_y,
                     data.acceleration_z,
                 ) {
-                    for (axis, value) in [('x', x), ('y', y), ('z', z)] {
+                    for (family, value) in [
+                        (&metrics::TAG_ACCELERATION_X, x),
+                        (&metrics::TAG_ACCELERATION_Y, y),
+                        (&metrics::TAG_ACCELERATION_Z, z),
+                    ] {
                         add_metric(
-                            &mut metrics,
-                            &format!("ruuvi_tag_acceleration_{axis}_g"),
+                            metrics,
+                            family,
                             &labels,
+                            timestamp,
                             f64::from(value) / 1000.0,
                         );
                     }
@@ -148,23 +408,27 @@ pub fn collect_metrics(state: &Measurements, names: &MacMapping) -> String {
 
                 // Device status
                 add_optional_metric(
-                    &mut metrics,
-                    "ruuvi_tag_battery_volts",
+                    metrics,
+                    &metrics::TAG_BATTERY,
                     &labels,
+                    timestamp,
                     data.battery_voltage.map(|v| f64::from(v) / 1000.0),
                 );
 
                 add_optional_metric(
-                    &mut metrics,
-                    "ruuvi_tag_tx_power_dBm",
+                    metrics,
+                    &metrics::TAG_TX_POWER,
                     &labels,
+                    timestamp,
                     data.tx_power,
                 );
             }
-            ruuvi_decoders::RuuviData::V6(data) => {
+            RuuviData::V6(data) => {
                 add_common_environmental_metrics(
-                    &mut metrics,
+                    metrics,
                     &labels,
+                    timestamp,
+                    options.derived_metrics,
                     data.measurement_sequence.map(u32::from),
                     data.temperature,
                     data.humidity,
@@ -172,8 +436,9 @@ pub fn collect_metrics(state: &Measurements, names: &MacMapping) -> String {
                 );
 
                 add_air_quality_metrics(
-                    &mut metrics,
+                    metrics,
                     &labels,
+                    timestamp,
                     data.pm2_5,
                     data.co2,
                     data.voc_index,
@@ -181,10 +446,12 @@ pub fn collect_metrics(state: &Measurements, names: &MacMapping) -> String {
                     data.luminosity,
                 );
             }
-            ruuvi_decoders::RuuviData::E1(data) => {
+            RuuviData::E1(data) => {
                 add_common_environmental_metrics(
-                    &mut metrics,
+                    metrics,
                     &labels,
+                    timestamp,
+                    options.derived_metrics,
                     data.measurement_sequence,
                     data.temperature,
                     data.humidity,
@@ -192,13 +459,20 @@ pub fn collect_metrics(state: &Measurements, names: &MacMapping) -> String {
                 );
 
                 // E1-specific PM metrics
-                add_optional_metric(&mut metrics, "ruuvi_tag_pm1_0_ugm3", &labels, data.pm1_0);
-                add_optional_metric(&mut metrics, "ruuvi_tag_pm4_0_ugm3", &labels, data.pm4_0);
-                add_optional_metric(&mut metrics, "ruuvi_tag_pm10_0_ugm3", &labels, data.pm10_0);
+                add_optional_metric(metrics, &metrics::TAG_PM1_0, &labels, timestamp, data.pm1_0);
+                add_optional_metric(metrics, &metrics::TAG_PM4_0, &labels, timestamp, data.pm4_0);
+                add_optional_metric(
+                    metrics,
+                    &metrics::TAG_PM10_0,
+                    &labels,
+                    timestamp,
+                    data.pm10_0,
+                );
 
                 add_air_quality_metrics(
-                    &mut metrics,
+                    metrics,
                     &labels,
+                    timestamp,
                     data.pm2_5,
                     data.co2,
                     data.voc_index,
@@ -208,27 +482,89 @@ pub fn collect_metrics(state: &Measurements, names: &MacMapping) -> String {
             }
         }
 
-        // Signal strength
-        add_metric(&mut metrics, "ruuvi_tag_rssi_dBm", &labels, tag.rssi);
-    }
+        // Signal strength as seen by each gateway hearing the tag
+        for (gw_mac, hearing) in &tag.heard_by {
+            let gw_mac = gw_mac.to_string();
+            add_metric(
+                metrics,
+                &metrics::TAG_RSSI,
+                &labels.clone().label("gw_mac", &gw_mac),
+                options.sample_timestamps.then_some(hearing.last_seen),
+                hearing.rssi,
+            );
+        }
 
-    metrics.join("\n") + "\n"
+        add_metric(
+            metrics,
+            &metrics::TAG_BEST_GATEWAY,
+            &labels
+                .clone()
+                .label("gw_mac", &tag.best_gateway.to_string()),
+            timestamp,
+            1,
+        );
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::rw_message::TagMessage;
+    use crate::metrics::Format;
+    use crate::rw_message::{GwMessage, TagMessage};
     use hifitime::Epoch;
 
+    fn collect(
+        state: &Measurements,
+        names: &MacMapping,
+        options: &CollectorOptions,
+        format: Format,
+        now: Epoch,
+    ) -> String {
+        let mut metrics = MetricsWriter::new();
+        collect_metrics(&mut metrics, state, names, options, now);
+        metrics.render(format)
+    }
+
+    fn now() -> Epoch {
+        Epoch::from_unix_seconds(1609459230.0)
+    }
+
+    fn gw_message(timestamp: f64, nonce: u64, tags: Vec<TagMessage>) -> GwMessage {
+        GwMessage {
+            coordinates: String::new(),
+            timestamp: Epoch::from_unix_seconds(timestamp),
+            nonce,
+            gw_mac: "AA:BB:CC:DD:EE:FF".parse().unwrap(),
+            tags,
+            invalid_tags: vec![],
+            invalid_macs: vec![],
+        }
+    }
+
+    /// A format 5 reading of 20.32 °C, 32.95 % and 100347 Pa heard at 1609459210.
+    fn v5_tag() -> TagMessage {
+        TagMessage {
+            mac: "DD:19:92:CB:60:21".parse().unwrap(),
+            data: hex::decode("0201061BFF9904050FE0337CC4ABFC1400340024A5B6EBA544DD1992CB6021")
+                .unwrap(),
+            timestamp: Epoch::from_unix_seconds(1609459210.0),
+            rssi: -55,
+        }
+    }
+
     #[test]
     fn test_collect_metrics_basic() {
         let mut measurements = Measurements::new();
-        measurements.mac = "AA:BB:CC:DD:EE:FF".to_string();
-        measurements.last_update = Epoch::from_unix_seconds(1234567890.0);
+        measurements.update_gateway(&gw_message(1234567890.0, 1, vec![]), now());
 
         let names = MacMapping::default();
-        let output = collect_metrics(&measurements, &names);
+        let output = collect(
+            &measurements,
+            &names,
+            &CollectorOptions::default(),
+            Format::Prometheus,
+            now(),
+        );
 
         assert!(output.contains("ruuvi_gateway_update_timestamp_seconds"));
         assert!(output.contains("gw_mac=\"AA:BB:CC:DD:EE:FF\""));
@@ -237,23 +573,26 @@ mod tests {
 
     #[test]
     fn test_collect_metrics_with_tag() {
-        let mut measurements = Measurements::new();
-        measurements.mac = "AA:BB:CC:DD:EE:FF".to_string();
-        measurements.last_update = Epoch::from_unix_seconds(1234567890.0);
-
         // Add a tag with data
         let data =
             hex::decode("0201061BFF9904050FE0337CC4ABFC1400340024A5B6EBA544DD1992CB6021").unwrap();
         let tag_msg = TagMessage {
-            name: "DD:19:92:CB:60:21".to_string(),
+            mac: "DD:19:92:CB:60:21".parse().unwrap(),
             data,
             timestamp: Epoch::from_unix_seconds(1234567890.0),
             rssi: -50,
         };
-        measurements.update_tag(&tag_msg);
+        let mut measurements = Measurements::new();
+        measurements.update_gateway(&gw_message(1234567890.0, 1, vec![tag_msg]), now());
 
         let names = MacMapping::default();
-        let output = collect_metrics(&measurements, &names);
+        let output = collect(
+            &measurements,
+            &names,
+            &CollectorOptions::default(),
+            Format::Prometheus,
+            now(),
+        );
 
         // Check tag metrics are present
         assert!(output.contains("ruuvi_tag_last_seen_timestamp_seconds"));
@@ -265,8 +604,7 @@ mod tests {
     #[test]
     fn test_collect_metrics_with_names() {
         let mut measurements = Measurements::new();
-        measurements.mac = "AA:BB:CC:DD:EE:FF".to_string();
-        measurements.last_update = Epoch::from_unix_seconds(1234567890.0);
+        measurements.update_gateway(&gw_message(1234567890.0, 1, vec![]), now());
 
         // Create mapping with names
         let yaml = r#"
@@ -277,7 +615,13 @@ mod tests {
         write!(temp_file, "{}", yaml).unwrap();
         let names = MacMapping::load(temp_file.path()).unwrap();
 
-        let output = collect_metrics(&measurements, &names);
+        let output = collect(
+            &measurements,
+            &names,
+            &CollectorOptions::default(),
+            Format::Prometheus,
+            now(),
+        );
 
         assert!(output.contains("name=\"Gateway 1\""));
     }
@@ -286,33 +630,36 @@ mod tests {
     fn test_collect_metrics_full_output() {
         // This test validates the complete output format to ensure refactoring
         // doesn't accidentally change the behavior of the system
-        let mut measurements = Measurements::new();
-        measurements.mac = "AA:BB:CC:DD:EE:FF".to_string();
-        measurements.last_update = Epoch::from_unix_seconds(1609459200.0); // 2021-01-01 00:00:00 UTC
-        measurements.last_nonce = Some(42);
-
         // Add a tag with complete data using RuuviTag format v5
         // Data format: 0x05 | temp | humidity | pressure | accel_x | accel_y | accel_z | battery+power | movement | sequence
         let data =
             hex::decode("0201061BFF9904050FE0337CC4ABFC1400340024A5B6EBA544DD1992CB6021").unwrap();
         let tag_msg = TagMessage {
-            name: "DD:19:92:CB:60:21".to_string(),
+            mac: "DD:19:92:CB:60:21".parse().unwrap(),
             data,
             timestamp: Epoch::from_unix_seconds(1609459210.0), // 10 seconds after gateway
             rssi: -55,
         };
-        measurements.update_tag(&tag_msg);
 
         // Add an E1 sensor with air quality data
         let e1_data =
             hex::decode("2BFF9904E1170C5668C79E0065007004BD11CA00C90A0213E0ACFFFFFFDECDEE10FFFFFFFFFFCBB8334C884F").unwrap();
         let e1_tag_msg = TagMessage {
-            name: "CB:B8:33:4C:88:4F".to_string(),
+            mac: "CB:B8:33:4C:88:4F".parse().unwrap(),
             data: e1_data,
             timestamp: Epoch::from_unix_seconds(1609459220.0), // 20 seconds after gateway
             rssi: -65,
         };
-        measurements.update_tag(&e1_tag_msg);
+
+        let mut measurements = Measurements::new();
+        measurements.update_gateway(
+            &gw_message(
+                1609459200.0, // 2021-01-01 00:00:00 UTC
+                42,
+                vec![tag_msg, e1_tag_msg],
+            ),
+            now(),
+        );
 
         // Create mapping with names
         let yaml = r#"
@@ -325,39 +672,407 @@ mod tests {
         write!(temp_file, "{}", yaml).unwrap();
         let names = MacMapping::load(temp_file.path()).unwrap();
 
-        let output = collect_metrics(&measurements, &names);
+        let output = collect(
+            &measurements,
+            &names,
+            &CollectorOptions::default(),
+            Format::Prometheus,
+            now(),
+        );
 
         // Expected output (order and exact format matter for this test)
-        let expected = r#"ruuvi_gateway_update_timestamp_seconds{gw_mac="AA:BB:CC:DD:EE:FF",name="Test Gateway"} 1609459200
+        let expected = r#"# HELP ruuvi_gateway_update_timestamp_seconds Timestamp of the latest update posted by the gateway.
+# TYPE ruuvi_gateway_update_timestamp_seconds gauge
+ruuvi_gateway_update_timestamp_seconds{gw_mac="AA:BB:CC:DD:EE:FF",name="Test Gateway"} 1609459200
+# HELP ruuvi_gateway_nonce Nonce of the latest update posted by the gateway.
+# TYPE ruuvi_gateway_nonce gauge
 ruuvi_gateway_nonce{gw_mac="AA:BB:CC:DD:EE:FF",name="Test Gateway"} 42
-ruuvi_tag_last_seen_timestamp_seconds{mac="CB:B8:33:4C:88:4F",gw_mac="AA:BB:CC:DD:EE:FF",name="Office"} 1609459220
-ruuvi_tag_sequence_number{mac="CB:B8:33:4C:88:4F",gw_mac="AA:BB:CC:DD:EE:FF",name="Office"} 14601710
-ruuvi_tag_temperature_celsius{mac="CB:B8:33:4C:88:4F",gw_mac="AA:BB:CC:DD:EE:FF",name="Office"} 29.5
-ruuvi_tag_humidity_ratio{mac="CB:B8:33:4C:88:4F",gw_mac="AA:BB:CC:DD:EE:FF",name="Office"} 0.553
-ruuvi_tag_pressure_pascals{mac="CB:B8:33:4C:88:4F",gw_mac="AA:BB:CC:DD:EE:FF",name="Office"} 101102
-ruuvi_tag_pm1_0_ugm3{mac="CB:B8:33:4C:88:4F",gw_mac="AA:BB:CC:DD:EE:FF",name="Office"} 10.100000000000001
-ruuvi_tag_pm4_0_ugm3{mac="CB:B8:33:4C:88:4F",gw_mac="AA:BB:CC:DD:EE:FF",name="Office"} 121.30000000000001
-ruuvi_tag_pm10_0_ugm3{mac="CB:B8:33:4C:88:4F",gw_mac="AA:BB:CC:DD:EE:FF",name="Office"} 455.40000000000003
-ruuvi_tag_pm2_5_ugm3{mac="CB:B8:33:4C:88:4F",gw_mac="AA:BB:CC:DD:EE:FF",name="Office"} 11.200000000000001
-ruuvi_tag_co2_ppm{mac="CB:B8:33:4C:88:4F",gw_mac="AA:BB:CC:DD:EE:FF",name="Office"} 201
-ruuvi_tag_voc_index{mac="CB:B8:33:4C:88:4F",gw_mac="AA:BB:CC:DD:EE:FF",name="Office"} 20
-ruuvi_tag_nox_index{mac="CB:B8:33:4C:88:4F",gw_mac="AA:BB:CC:DD:EE:FF",name="Office"} 4
-ruuvi_tag_luminosity_lux{mac="CB:B8:33:4C:88:4F",gw_mac="AA:BB:CC:DD:EE:FF",name="Office"} 13027
-ruuvi_tag_rssi_dBm{mac="CB:B8:33:4C:88:4F",gw_mac="AA:BB:CC:DD:EE:FF",name="Office"} -65
-ruuvi_tag_last_seen_timestamp_seconds{mac="DD:19:92:CB:60:21",gw_mac="AA:BB:CC:DD:EE:FF",name="Living Room"} 1609459210
-ruuvi_tag_sequence_number{mac="DD:19:92:CB:60:21",gw_mac="AA:BB:CC:DD:EE:FF",name="Living Room"} 42308
-ruuvi_tag_temperature_celsius{mac="DD:19:92:CB:60:21",gw_mac="AA:BB:CC:DD:EE:FF",name="Living Room"} 20.32
-ruuvi_tag_humidity_ratio{mac="DD:19:92:CB:60:21",gw_mac="AA:BB:CC:DD:EE:FF",name="Living Room"} 0.3295
-ruuvi_tag_pressure_pascals{mac="DD:19:92:CB:60:21",gw_mac="AA:BB:CC:DD:EE:FF",name="Living Room"} 100347
-ruuvi_tag_movement_counter{mac="DD:19:92:CB:60:21",gw_mac="AA:BB:CC:DD:EE:FF",name="Living Room"} 235
-ruuvi_tag_acceleration_x_g{mac="DD:19:92:CB:60:21",gw_mac="AA:BB:CC:DD:EE:FF",name="Living Room"} -1.004
-ruuvi_tag_acceleration_y_g{mac="DD:19:92:CB:60:21",gw_mac="AA:BB:CC:DD:EE:FF",name="Living Room"} 0.052
-ruuvi_tag_acceleration_z_g{mac="DD:19:92:CB:60:21",gw_mac="AA:BB:CC:DD:EE:FF",name="Living Room"} 0.036
-ruuvi_tag_battery_volts{mac="DD:19:92:CB:60:21",gw_mac="AA:BB:CC:DD:EE:FF",name="Living Room"} 2.925
-ruuvi_tag_tx_power_dBm{mac="DD:19:92:CB:60:21",gw_mac="AA:BB:CC:DD:EE:FF",name="Living Room"} 4
-ruuvi_tag_rssi_dBm{mac="DD:19:92:CB:60:21",gw_mac="AA:BB:CC:DD:EE:FF",name="Living Room"} -55
+# HELP ruuvi_tag_last_seen_timestamp_seconds Timestamp of the latest reading received from the tag.
+# TYPE ruuvi_tag_last_seen_timestamp_seconds gauge
+ruuvi_tag_last_seen_timestamp_seconds{mac="CB:B8:33:4C:88:4F",name="Office"} 1609459220
+ruuvi_tag_last_seen_timestamp_seconds{mac="DD:19:92:CB:60:21",name="Living Room"} 1609459210
+# HELP ruuvi_tag_up Whether the tag has been heard within its timeout.
+# TYPE ruuvi_tag_up gauge
+ruuvi_tag_up{mac="CB:B8:33:4C:88:4F",name="Office"} 1
+ruuvi_tag_up{mac="DD:19:92:CB:60:21",name="Living Room"} 1
+# HELP ruuvi_tag_sequence_number Measurement sequence number reported by the tag.
+# TYPE ruuvi_tag_sequence_number counter
+ruuvi_tag_sequence_number{mac="CB:B8:33:4C:88:4F",name="Office"} 14601710
+ruuvi_tag_sequence_number{mac="DD:19:92:CB:60:21",name="Living Room"} 42308
+# HELP ruuvi_tag_packets_received_total Distinct readings received from the tag, counted by measurement sequence number.
+# TYPE ruuvi_tag_packets_received_total counter
+ruuvi_tag_packets_received_total{mac="CB:B8:33:4C:88:4F",name="Office"} 1
+ruuvi_tag_packets_received_total{mac="DD:19:92:CB:60:21",name="Living Room"} 1
+# HELP ruuvi_tag_packets_missed_total Measurement sequence numbers of the tag skipped between received readings.
+# TYPE ruuvi_tag_packets_missed_total counter
+ruuvi_tag_packets_missed_total{mac="CB:B8:33:4C:88:4F",name="Office"} 0
+ruuvi_tag_packets_missed_total{mac="DD:19:92:CB:60:21",name="Living Room"} 0
+# HELP ruuvi_tag_temperature_celsius Temperature measured by the tag.
+# TYPE ruuvi_tag_temperature_celsius gauge
+ruuvi_tag_temperature_celsius{mac="CB:B8:33:4C:88:4F",name="Office"} 29.5
+ruuvi_tag_temperature_celsius{mac="DD:19:92:CB:60:21",name="Living Room"} 20.32
+# HELP ruuvi_tag_humidity_ratio Relative humidity measured by the tag.
+# TYPE ruuvi_tag_humidity_ratio gauge
+ruuvi_tag_humidity_ratio{mac="CB:B8:33:4C:88:4F",name="Office"} 0.553
+ruuvi_tag_humidity_ratio{mac="DD:19:92:CB:60:21",name="Living Room"} 0.3295
+# HELP ruuvi_tag_pressure_pascals Air pressure measured by the tag.
+# TYPE ruuvi_tag_pressure_pascals gauge
+ruuvi_tag_pressure_pascals{mac="CB:B8:33:4C:88:4F",name="Office"} 101102
+ruuvi_tag_pressure_pascals{mac="DD:19:92:CB:60:21",name="Living Room"} 100347
+# HELP ruuvi_tag_movement_counter Number of movements detected by the tag's accelerometer.
+# TYPE ruuvi_tag_movement_counter counter
+ruuvi_tag_movement_counter{mac="DD:19:92:CB:60:21",name="Living Room"} 235
+# HELP ruuvi_tag_acceleration_x_g Acceleration along the X axis of the tag.
+# TYPE ruuvi_tag_acceleration_x_g gauge
+ruuvi_tag_acceleration_x_g{mac="DD:19:92:CB:60:21",name="Living Room"} -1.004
+# HELP ruuvi_tag_acceleration_y_g Acceleration along the Y axis of the tag.
+# TYPE ruuvi_tag_acceleration_y_g gauge
+ruuvi_tag_acceleration_y_g{mac="DD:19:92:CB:60:21",name="Living Room"} 0.052
+# HELP ruuvi_tag_acceleration_z_g Acceleration along the Z axis of the tag.
+# TYPE ruuvi_tag_acceleration_z_g gauge
+ruuvi_tag_acceleration_z_g{mac="DD:19:92:CB:60:21",name="Living Room"} 0.036
+# HELP ruuvi_tag_battery_volts Battery voltage of the tag.
+# TYPE ruuvi_tag_battery_volts gauge
+ruuvi_tag_battery_volts{mac="DD:19:92:CB:60:21",name="Living Room"} 2.925
+# HELP ruuvi_tag_tx_power_dBm Transmit power of the tag.
+# TYPE ruuvi_tag_tx_power_dBm gauge
+ruuvi_tag_tx_power_dBm{mac="DD:19:92:CB:60:21",name="Living Room"} 4
+# HELP ruuvi_tag_pm1_0_ugm3 PM1.0 particulate matter concentration in µg/m³.
+# TYPE ruuvi_tag_pm1_0_ugm3 gauge
+ruuvi_tag_pm1_0_ugm3{mac="CB:B8:33:4C:88:4F",name="Office"} 10.100000000000001
+# HELP ruuvi_tag_pm2_5_ugm3 PM2.5 particulate matter concentration in µg/m³.
+# TYPE ruuvi_tag_pm2_5_ugm3 gauge
+ruuvi_tag_pm2_5_ugm3{mac="CB:B8:33:4C:88:4F",name="Office"} 11.200000000000001
+# HELP ruuvi_tag_pm4_0_ugm3 PM4.0 particulate matter concentration in µg/m³.
+# TYPE ruuvi_tag_pm4_0_ugm3 gauge
+ruuvi_tag_pm4_0_ugm3{mac="CB:B8:33:4C:88:4F",name="Office"} 121.30000000000001
+# HELP ruuvi_tag_pm10_0_ugm3 PM10 particulate matter concentration in µg/m³.
+# TYPE ruuvi_tag_pm10_0_ugm3 gauge
+ruuvi_tag_pm10_0_ugm3{mac="CB:B8:33:4C:88:4F",name="Office"} 455.40000000000003
+# HELP ruuvi_tag_co2_ppm CO2 concentration measured by the tag.
+# TYPE ruuvi_tag_co2_ppm gauge
+ruuvi_tag_co2_ppm{mac="CB:B8:33:4C:88:4F",name="Office"} 201
+# HELP ruuvi_tag_voc_index Volatile organic compounds index measured by the tag.
+# TYPE ruuvi_tag_voc_index gauge
+ruuvi_tag_voc_index{mac="CB:B8:33:4C:88:4F",name="Office"} 20
+# HELP ruuvi_tag_nox_index Nitrogen oxides index measured by the tag.
+# TYPE ruuvi_tag_nox_index gauge
+ruuvi_tag_nox_index{mac="CB:B8:33:4C:88:4F",name="Office"} 4
+# HELP ruuvi_tag_luminosity_lux Illuminance measured by the tag.
+# TYPE ruuvi_tag_luminosity_lux gauge
+ruuvi_tag_luminosity_lux{mac="CB:B8:33:4C:88:4F",name="Office"} 13027
+# HELP ruuvi_tag_rssi_dBm Signal strength of the tag as received by the gateway.
+# TYPE ruuvi_tag_rssi_dBm gauge
+ruuvi_tag_rssi_dBm{mac="CB:B8:33:4C:88:4F",name="Office",gw_mac="AA:BB:CC:DD:EE:FF"} -65
+ruuvi_tag_rssi_dBm{mac="DD:19:92:CB:60:21",name="Living Room",gw_mac="AA:BB:CC:DD:EE:FF"} -55
+# HELP ruuvi_tag_best_gateway Gateway that delivered the tag's current reading.
+# TYPE ruuvi_tag_best_gateway gauge
+ruuvi_tag_best_gateway{mac="CB:B8:33:4C:88:4F",name="Office",gw_mac="AA:BB:CC:DD:EE:FF"} 1
+ruuvi_tag_best_gateway{mac="DD:19:92:CB:60:21",name="Living Room",gw_mac="AA:BB:CC:DD:EE:FF"} 1
 "#;
 
         assert_eq!(output, expected, "Output format has changed!");
     }
+
+    #[test]
+    fn test_collect_metrics_multiple_gateways() {
+        let data =
+            hex::decode("0201061BFF9904050FE0337CC4ABFC1400340024A5B6EBA544DD1992CB6021").unwrap();
+        let tag_msg = |rssi| TagMessage {
+            mac: "DD:19:92:CB:60:21".parse().unwrap(),
+            data: data.clone(),
+            timestamp: Epoch::from_unix_seconds(1609459210.0),
+            rssi,
+        };
+
+        let mut measurements = Measurements::new();
+        measurements.update_gateway(&gw_message(1609459200.0, 1, vec![tag_msg(-75)]), now());
+        measurements.update_gateway(
+            &GwMessage {
+                gw_mac: "11:22:33:44:55:66".parse().unwrap(),
+                ..gw_message(1609459300.0, 2, vec![tag_msg(-55)])
+            },
+            now(),
+        );
+
+        let output = collect(
+            &measurements,
+            &MacMapping::default(),
+            &CollectorOptions::default(),
+            Format::Prometheus,
+            now(),
+        );
+
+        assert!(output.contains(
+            "ruuvi_gateway_update_timestamp_seconds{gw_mac=\"11:22:33:44:55:66\"} 1609459300\n"
+        ));
+        assert!(output.contains(
+            "ruuvi_gateway_update_timestamp_seconds{gw_mac=\"AA:BB:CC:DD:EE:FF\"} 1609459200\n"
+        ));
+        assert_eq!(
+            output.matches("ruuvi_tag_temperature_celsius{").count(),
+            1,
+            "Tag heard by two gateways must be exported once"
+        );
+        assert!(output.contains(
+            "ruuvi_tag_rssi_dBm{mac=\"DD:19:92:CB:60:21\",gw_mac=\"11:22:33:44:55:66\"} -55\n"
+        ));
+        assert!(output.contains(
+            "ruuvi_tag_rssi_dBm{mac=\"DD:19:92:CB:60:21\",gw_mac=\"AA:BB:CC:DD:EE:FF\"} -75\n"
+        ));
+        assert!(output.contains(
+            "ruuvi_tag_best_gateway{mac=\"DD:19:92:CB:60:21\",gw_mac=\"11:22:33:44:55:66\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn test_collect_metrics_tag_timeout() {
+        let data =
+            hex::decode("0201061BFF9904050FE0337CC4ABFC1400340024A5B6EBA544DD1992CB6021").unwrap();
+        let tag_msg = TagMessage {
+            mac: "DD:19:92:CB:60:21".parse().unwrap(),
+            data,
+            timestamp: Epoch::from_unix_seconds(1609459200.0),
+            rssi: -55,
+        };
+        let mut measurements = Measurements::new();
+        measurements.update_gateway(
+            &gw_message(1609459200.0, 1, vec![tag_msg]),
+            Epoch::from_unix_seconds(1609459200.0),
+        );
+
+        let names = MacMapping::default();
+        let options = CollectorOptions {
+            tag_timeout: Some(Duration::from_seconds(60.0)),
+            ..CollectorOptions::default()
+        };
+        let collect = |seconds| {
+            collect(
+                &measurements,
+                &names,
+                &options,
+                Format::Prometheus,
+                Epoch::from_unix_seconds(seconds),
+            )
+        };
+
+        let up = collect(1609459260.0);
+        assert!(up.contains("ruuvi_tag_up{mac=\"DD:19:92:CB:60:21\"} 1\n"));
+        assert!(up.contains("ruuvi_tag_temperature_celsius"));
+
+        let down = collect(1609459261.0);
+        assert!(down.contains("ruuvi_tag_up{mac=\"DD:19:92:CB:60:21\"} 0\n"));
+        assert!(!down.contains("ruuvi_tag_temperature_celsius"));
+
+        let expired = collect(1609459321.0);
+        assert!(!expired.contains("ruuvi_tag_"));
+        assert!(expired.contains("ruuvi_gateway_update_timestamp_seconds"));
+    }
+
+    #[test]
+    fn test_collect_metrics_openmetrics_with_timestamps() {
+        let mut measurements = Measurements::new();
+        measurements.update_gateway(&gw_message(1609459200.0, 1, vec![v5_tag()]), now());
+
+        let options = CollectorOptions {
+            sample_timestamps: true,
+            ..CollectorOptions::default()
+        };
+        let output = collect(
+            &measurements,
+            &MacMapping::default(),
+            &options,
+            Format::OpenMetrics,
+            now(),
+        );
+
+        assert!(output.contains("# UNIT ruuvi_tag_temperature_celsius celsius\n"));
+        assert!(output.contains(
+            "ruuvi_tag_temperature_celsius{mac=\"DD:19:92:CB:60:21\"} 20.32 1609459210\n"
+        ));
+        assert!(output.contains(
+            "ruuvi_tag_movement_counter_total{mac=\"DD:19:92:CB:60:21\"} 235 1609459210\n"
+        ));
+        assert!(output.contains(
+            "ruuvi_tag_sequence_number_total{mac=\"DD:19:92:CB:60:21\"} 42308 1609459210\n"
+        ));
+        // Liveness is evaluated at scrape time
+        assert!(output.contains("ruuvi_tag_up{mac=\"DD:19:92:CB:60:21\"} 1\n"));
+        assert!(output.ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn test_collect_metrics_escapes_mapped_names() {
+        let mut measurements = Measurements::new();
+        measurements.update_gateway(&gw_message(1234567890.0, 1, vec![]), now());
+
+        let yaml = r#"
+            "AA:BB:CC:DD:EE:FF": "Kid's \"room\" \\ upstairs\n2nd floor"
+        "#;
+        let mut temp_file = tempfile::NamedTempFile::new().unwrap();
+        use std::io::Write;
+        write!(temp_file, "{}", yaml).unwrap();
+        let names = MacMapping::load(temp_file.path()).unwrap();
+
+        let output = collect(
+            &measurements,
+            &names,
+            &CollectorOptions::default(),
+            Format::Prometheus,
+            now(),
+        );
+
+        assert!(output.contains(r#"name="Kid's \"room\" \\ upstairs\n2nd floor""#));
+        assert_eq!(output.lines().count(), 6);
+    }
+
+    #[test]
+    fn test_collect_metrics_sample_timestamps() {
+        let mut measurements = Measurements::new();
+        measurements.update_gateway(&gw_message(1609459200.0, 1, vec![v5_tag()]), now());
+
+        let options = CollectorOptions {
+            sample_timestamps: true,
+            ..CollectorOptions::default()
+        };
+        let output = collect(
+            &measurements,
+            &MacMapping::default(),
+            &options,
+            Format::Prometheus,
+            now(),
+        );
+
+        // Tag readings carry the time they were heard, in milliseconds
+        assert!(output.contains(
+            "ruuvi_tag_temperature_celsius{mac=\"DD:19:92:CB:60:21\"} 20.32 1609459210000\n"
+        ));
+        assert!(output.contains(
+            "ruuvi_tag_rssi_dBm{mac=\"DD:19:92:CB:60:21\",gw_mac=\"AA:BB:CC:DD:EE:FF\"} -55 1609459210000\n"
+        ));
+        // Gateway state and liveness are not tied to a reading
+        assert!(output.contains(
+            "ruuvi_gateway_update_timestamp_seconds{gw_mac=\"AA:BB:CC:DD:EE:FF\"} 1609459200\n"
+        ));
+        assert!(output.contains("ruuvi_tag_up{mac=\"DD:19:92:CB:60:21\"} 1\n"));
+    }
+
+    #[test]
+    fn test_collect_metrics_with_tag_metadata() {
+        let mut measurements = Measurements::new();
+        measurements.update_gateway(&gw_message(1609459200.0, 1, vec![v5_tag()]), now());
+
+        let yaml = r#"
+            "DD:19:92:CB:60:21":
+                name: "Bedroom"
+                floor: 1
+                labels:
+                    sensor_kind: "indoor"
+        "#;
+        let mut temp_file = tempfile::NamedTempFile::new().unwrap();
+        use std::io::Write;
+        write!(temp_file, "{}", yaml).unwrap();
+        let names = MacMapping::load(temp_file.path()).unwrap();
+
+        let output = collect(
+            &measurements,
+            &names,
+            &CollectorOptions::default(),
+            Format::Prometheus,
+            now(),
+        );
+
+        assert!(output.contains(
+            "ruuvi_tag_temperature_celsius{mac=\"DD:19:92:CB:60:21\",name=\"Bedroom\",floor=\"1\",sensor_kind=\"indoor\"} 20.32\n"
+        ));
+        assert!(output.contains(
+            "ruuvi_tag_rssi_dBm{mac=\"DD:19:92:CB:60:21\",name=\"Bedroom\",floor=\"1\",sensor_kind=\"indoor\",gw_mac=\"AA:BB:CC:DD:EE:FF\"} -55\n"
+        ));
+    }
+
+    #[test]
+    fn test_collect_metrics_derived_metrics() {
+        let mut measurements = Measurements::new();
+        measurements.update_gateway(&gw_message(1609459200.0, 1, vec![v5_tag()]), now());
+
+        let collect_with = |derived: &str| {
+            let options = CollectorOptions {
+                derived_metrics: derived.parse().unwrap(),
+                ..CollectorOptions::default()
+            };
+            collect(
+                &measurements,
+                &MacMapping::default(),
+                &options,
+                Format::Prometheus,
+                now(),
+            )
+        };
+
+        let output = collect_with("dew_point,mixing_ratio");
+        // 20.32 °C at 32.95 % relative humidity and 100347 Pa
+        let dew_point = psychrometrics::dew_point(20.32, 32.95).unwrap();
+        assert!(output.contains(&format!(
+            "ruuvi_tag_dew_point_celsius{{mac=\"DD:19:92:CB:60:21\"}} {dew_point}\n"
+        )));
+        assert!((3.3..3.5).contains(&dew_point), "{dew_point}");
+        assert!(output.contains("ruuvi_tag_mixing_ratio{mac=\"DD:19:92:CB:60:21\"} 0.00"));
+        assert!(!output.contains("ruuvi_tag_frost_point_celsius"));
+        assert!(!output.contains("ruuvi_tag_absolute_humidity_gm3"));
+
+        let output = collect_with("all");
+        for family in [
+            "ruuvi_tag_frost_point_celsius",
+            "ruuvi_tag_absolute_humidity_gm3",
+            "ruuvi_tag_vapour_pressure_deficit_pascals",
+        ] {
+            assert!(
+                output.contains(&format!("# TYPE {family} gauge\n")),
+                "{family}"
+            );
+        }
+
+        // Disabled by default
+        assert!(!collect_with("").contains("ruuvi_tag_dew_point_celsius"));
+    }
+
+    #[test]
+    fn test_collect_metrics_calibration() {
+        let mut measurements = Measurements::new();
+        measurements.update_gateway(&gw_message(1609459200.0, 1, vec![v5_tag()]), now());
+        let names: MacMapping = serde_yaml::from_str(
+            r#"
+            "DD:19:92:CB:60:21":
+                calibration:
+                    temperature_offset: -0.5
+                    pressure_offset: 200
+            "#,
+        )
+        .unwrap();
+
+        let collect_with = |raw_metrics| {
+            let options = CollectorOptions {
+                raw_metrics,
+                ..CollectorOptions::default()
+            };
+            collect(&measurements, &names, &options, Format::Prometheus, now())
+        };
+
+        let output = collect_with(false);
+        assert!(output.contains("ruuvi_tag_temperature_celsius{mac=\"DD:19:92:CB:60:21\"} 19.82\n"));
+        assert!(output.contains("ruuvi_tag_pressure_pascals{mac=\"DD:19:92:CB:60:21\"} 100547\n"));
+        assert!(output.contains("ruuvi_tag_humidity_ratio{mac=\"DD:19:92:CB:60:21\"} 0.3295\n"));
+        assert!(!output.contains("_raw_"));
+
+        // Only the calibrated quantities have raw metrics
+        let output = collect_with(true);
+        assert!(output.contains("ruuvi_tag_temperature_celsius{mac=\"DD:19:92:CB:60:21\"} 19.82\n"));
+        assert!(
+            output.contains("ruuvi_tag_temperature_raw_celsius{mac=\"DD:19:92:CB:60:21\"} 20.32\n")
+        );
+        assert!(
+            output.contains("ruuvi_tag_pressure_raw_pascals{mac=\"DD:19:92:CB:60:21\"} 100347\n")
+        );
+        assert!(!output.contains("ruuvi_tag_humidity_raw_ratio"));
+    }
 }
@@ -1,37 +1,512 @@
-use clap::Parser;
-use serde::Deserialize;
+use clap::{Parser, Subcommand};
+use hifitime::Duration;
+use serde::{
+    de::{self, value::MapAccessDeserializer, MapAccess, Visitor},
+    Deserialize, Deserializer,
+};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
+    fmt,
     fs::File,
     io::BufReader,
+    net::{IpAddr, Ipv4Addr},
     path::{Path, PathBuf},
+    str::FromStr,
 };
+use thiserror::Error;
 
-#[derive(Parser)]
-#[command(version, about)]
-pub struct Config {
-    /// Port to listen on
-    #[arg(short, long, default_value_t = 9000)]
-    pub port: u16,
+use crate::auth::{IngestAuthConfig, MetricsAuthConfig};
+use crate::calibration::Calibration;
+use crate::logging::{LogFormat, LogLevel};
+use crate::mac::MacAddress;
+use crate::metrics::is_valid_label_name;
+use crate::psychrometrics::DerivedMetrics;
+use crate::tls::TlsConfig;
+
+const PRECEDENCE: &str = "\
+Settings are taken from, in order of precedence:
+  1. command line options
+  2. RUUVI_EXPORTER_* environment variables, e.g. RUUVI_EXPORTER_PORT=9000
+  3. the YAML file given with --config
+  4. built-in defaults";
 
-    /// Interface to bind to
-    #[arg(short, long, default_value = "0.0.0.0")]
-    pub interface: String,
+// Every option can also be set in the config file or through the environment, see
+// `Config::resolve`.
+#[derive(Parser, Debug, Default)]
+#[command(version, about, after_help = PRECEDENCE)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Path to YAML config file
+    #[arg(short, long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Port to listen on [default: 9000]
+    #[arg(short, long)]
+    pub port: Option<u16>,
 
-    /// Path to YAML config file with MAC address mappings
+    /// Interface to bind to [default: 0.0.0.0]
     #[arg(short, long)]
+    pub interface: Option<IpAddr>,
+
+    /// Path to YAML file with MAC address mappings. Changes are picked up within about 5
+    /// seconds, or immediately on SIGHUP
+    #[arg(short, long, global = true)]
     pub mac_mapping: Option<PathBuf>,
+
+    /// Seconds after which a tag that has not been heard is reported as down. The tag is
+    /// dropped from the metrics once it has been down for another timeout period.
+    #[arg(long, value_name = "SECONDS")]
+    pub tag_timeout: Option<u64>,
+
+    /// Seconds within which a gateway must have posted for /ready to report the exporter as
+    /// ready [default: 300]
+    #[arg(long, value_name = "SECONDS")]
+    pub ready_window: Option<u64>,
+
+    /// Attach the time each tag was heard to its samples instead of using the scrape time
+    #[arg(
+        long,
+        value_name = "BOOL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    pub sample_timestamps: Option<bool>,
+
+    /// Comma separated psychrometric metrics to export: dew_point, frost_point,
+    /// absolute_humidity, mixing_ratio, vapour_pressure_deficit or all
+    #[arg(long, value_name = "LIST")]
+    pub derived_metrics: Option<DerivedMetrics>,
+
+    /// Also export the uncalibrated readings of tags with a calibration in the mapping, as
+    /// ruuvi_tag_*_raw_* metrics
+    #[arg(
+        long,
+        value_name = "BOOL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    pub raw_metrics: Option<bool>,
+
+    /// PEM file with the TLS certificate chain. Serves HTTPS together with --tls-key.
+    #[arg(long, value_name = "PATH", global = true)]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the TLS certificate
+    #[arg(long, value_name = "PATH", global = true)]
+    pub tls_key: Option<PathBuf>,
+
+    /// Most verbose messages to log: off, error, warn, info, debug or trace [default: info]
+    #[arg(long, value_name = "LEVEL")]
+    pub log_level: Option<LogLevel>,
+
+    /// Log as human readable text or as one JSON object per line [default: text]
+    #[arg(long, value_name = "FORMAT")]
+    pub log_format: Option<LogFormat>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Check the configuration and the mapping file, report all problems found and exit
+    CheckConfig,
 }
 
+/// Contents of the `--config` file. All settings are optional.
 #[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    pub port: Option<u16>,
+    pub interface: Option<IpAddr>,
+    /// Path to the mapping file, relative to the config file
+    pub mac_mapping: Option<PathBuf>,
+    /// Tag mapping given inline, in the same format as the mapping file
+    pub tags: Option<MacMapping>,
+    pub tag_timeout: Option<u64>,
+    pub ready_window: Option<u64>,
+    pub sample_timestamps: Option<bool>,
+    /// Psychrometric metrics to export, as a list of names
+    pub derived_metrics: Option<DerivedMetrics>,
+    pub raw_metrics: Option<bool>,
+    /// Credentials required from gateways posting measurements
+    pub ingest_auth: Option<IngestAuthConfig>,
+    /// Gateways allowed to post measurements, all if not set
+    pub allowed_gateways: Option<Vec<MacAddress>>,
+    /// Tags that are exported, all if not set
+    pub allowed_tags: Option<Vec<MacAddress>>,
+    /// Credentials required for scraping the metrics and reading the tag API
+    pub metrics_auth: Option<MetricsAuthConfig>,
+    /// Certificate and key for serving HTTPS, relative to the config file
+    pub tls: Option<TlsConfig>,
+    pub log_level: Option<LogLevel>,
+    pub log_format: Option<LogFormat>,
+}
+
+impl FileConfig {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let file = File::open(path).map_err(|err| ConfigError::Io(path.to_path_buf(), err))?;
+        let mut config: Self = serde_yaml::from_reader(BufReader::new(file))
+            .map_err(|err| ConfigError::Parse(path.to_path_buf(), err))?;
+        if config.mac_mapping.is_some() && config.tags.is_some() {
+            return Err(ConfigError::MappingConflict(path.to_path_buf()));
+        }
+        if let Some(dir) = path.parent() {
+            if let Some(mapping) = &mut config.mac_mapping {
+                *mapping = dir.join(&mapping);
+            }
+            if let Some(tls) = &mut config.tls {
+                tls.cert = dir.join(&tls.cert);
+                tls.key = dir.join(&tls.key);
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read config file {0:?}: {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("Failed to parse config file {0:?}: {1}")]
+    Parse(PathBuf, serde_yaml::Error),
+    #[error("Config file {0:?} sets both mac_mapping and tags")]
+    MappingConflict(PathBuf),
+    #[error("TLS needs both a certificate and a key")]
+    IncompleteTls,
+    #[error("Invalid value {value:?} in environment variable {name}: {reason}")]
+    Env {
+        name: String,
+        value: String,
+        reason: String,
+    },
+}
+
+/// Prefix of the environment variables overriding the config file.
+pub const ENV_PREFIX: &str = "RUUVI_EXPORTER_";
+
+/// Effective configuration after merging all sources, see `PRECEDENCE`.
+#[derive(Debug)]
+pub struct Config {
+    pub port: u16,
+    pub interface: IpAddr,
+    /// Mapping file, which is reloaded when it changes
+    pub mac_mapping: Option<PathBuf>,
+    /// Mapping given inline in the config file, used if there is no mapping file
+    pub tags: Option<MacMapping>,
+    pub tag_timeout: Option<u64>,
+    pub ready_window: u64,
+    pub sample_timestamps: bool,
+    pub derived_metrics: DerivedMetrics,
+    pub raw_metrics: bool,
+    pub ingest_auth: IngestAuthConfig,
+    pub allowed_gateways: Option<Vec<MacAddress>>,
+    pub allowed_tags: Option<Vec<MacAddress>>,
+    pub metrics_auth: MetricsAuthConfig,
+    /// Serve HTTPS instead of HTTP
+    pub tls: Option<TlsConfig>,
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
+}
+
+impl Config {
+    /// Merges the command line, the environment and the config file. `env` looks up an
+    /// environment variable by name.
+    pub fn resolve(cli: Cli, env: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let path: Option<PathBuf> = cli_or_env(cli.config, &env, "CONFIG")?;
+        let file = match &path {
+            Some(path) => FileConfig::load(path)?,
+            None => FileConfig::default(),
+        };
+
+        let (mac_mapping, tags) = match cli_or_env(cli.mac_mapping, &env, "MAC_MAPPING")? {
+            Some(path) => (Some(path), None),
+            None => (file.mac_mapping, file.tags),
+        };
+
+        let (file_cert, file_key) = file.tls.map(|tls| (tls.cert, tls.key)).unzip();
+        let tls = match (
+            cli_or_env(cli.tls_cert, &env, "TLS_CERT")?.or(file_cert),
+            cli_or_env(cli.tls_key, &env, "TLS_KEY")?.or(file_key),
+        ) {
+            (Some(cert), Some(key)) => Some(TlsConfig { cert, key }),
+            (None, None) => None,
+            _ => return Err(ConfigError::IncompleteTls),
+        };
+
+        Ok(Self {
+            port: cli_or_env(cli.port, &env, "PORT")?
+                .or(file.port)
+                .unwrap_or(9000),
+            interface: cli_or_env(cli.interface, &env, "INTERFACE")?
+                .or(file.interface)
+                .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            mac_mapping,
+            tags,
+            tag_timeout: cli_or_env(cli.tag_timeout, &env, "TAG_TIMEOUT")?.or(file.tag_timeout),
+            ready_window: cli_or_env(cli.ready_window, &env, "READY_WINDOW")?
+                .or(file.ready_window)
+                .unwrap_or(300),
+            sample_timestamps: cli_or_env(cli.sample_timestamps, &env, "SAMPLE_TIMESTAMPS")?
+                .or(file.sample_timestamps)
+                .unwrap_or(false),
+            derived_metrics: cli_or_env(cli.derived_metrics, &env, "DERIVED_METRICS")?
+                .or(file.derived_metrics)
+                .unwrap_or_default(),
+            raw_metrics: cli_or_env(cli.raw_metrics, &env, "RAW_METRICS")?
+                .or(file.raw_metrics)
+                .unwrap_or(false),
+            ingest_auth: file.ingest_auth.unwrap_or_default(),
+            allowed_gateways: file.allowed_gateways,
+            allowed_tags: file.allowed_tags,
+            metrics_auth: file.metrics_auth.unwrap_or_default(),
+            tls,
+            log_level: cli_or_env(cli.log_level, &env, "LOG_LEVEL")?
+                .or(file.log_level)
+                .unwrap_or_default(),
+            log_format: cli_or_env(cli.log_format, &env, "LOG_FORMAT")?
+                .or(file.log_format)
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// Returns the command line value if given, otherwise parses the environment variable `key`.
+fn cli_or_env<T>(
+    cli: Option<T>,
+    env: impl Fn(&str) -> Option<String>,
+    key: &str,
+) -> Result<Option<T>, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    if cli.is_some() {
+        return Ok(cli);
+    }
+    let name = format!("{ENV_PREFIX}{key}");
+    let Some(value) = env(&name) else {
+        return Ok(None);
+    };
+    match value.parse() {
+        Ok(value) => Ok(Some(value)),
+        Err(err) => Err(ConfigError::Env {
+            name,
+            value,
+            reason: err.to_string(),
+        }),
+    }
+}
+
+/// Labels set by the exporter itself, which the mapping file cannot override.
+const RESERVED_LABELS: &[&str] = &["mac", "gw_mac", "name", "location", "floor", "building"];
+
+/// Settings of a single tag in the mapping file.
+#[derive(Debug, Deserialize, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TagEntry {
+    pub name: Option<String>,
+    pub location: Option<LabelValue>,
+    pub floor: Option<LabelValue>,
+    pub building: Option<LabelValue>,
+    /// Additional labels attached to the tag's metrics
+    #[serde(default)]
+    pub labels: BTreeMap<String, LabelValue>,
+    /// Tag specific override of `--tag-timeout`, in seconds
+    pub timeout: Option<u64>,
+    /// Corrections applied to the tag's readings
+    #[serde(default)]
+    pub calibration: Calibration,
+}
+
+impl TagEntry {
+    /// Labels describing the tag besides its name, in a stable order.
+    pub fn extra_labels(&self) -> impl Iterator<Item = (&str, &str)> {
+        [
+            ("location", &self.location),
+            ("floor", &self.floor),
+            ("building", &self.building),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key, value.as_ref()?.0.as_str())))
+        .chain(
+            self.labels
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.0.as_str())),
+        )
+    }
+
+    /// Parses a single mapping file entry, which can be a plain name or a map of settings.
+    pub fn from_value(value: serde_yaml::Value) -> Result<Self, serde_yaml::Error> {
+        serde_yaml::from_value(value).map(|RawTagEntry(entry)| entry)
+    }
+
+    /// Returns every reason why the extra labels or the calibration of the tag `mac` cannot be
+    /// used.
+    pub fn problems(&self, mac: MacAddress) -> Vec<MappingError> {
+        let mut problems = Vec::new();
+        for label in self.labels.keys() {
+            let label = label.clone();
+            if RESERVED_LABELS.contains(&label.as_str()) {
+                problems.push(MappingError::ReservedLabel { mac, label });
+            } else if !is_valid_label_name(&label) {
+                problems.push(MappingError::InvalidLabelName { mac, label });
+            }
+        }
+        if let Some(field) = self.calibration.invalid_field() {
+            problems.push(MappingError::InvalidCalibration { mac, field });
+        }
+        problems
+    }
+}
+
+/// A label value in the mapping file. Numbers and booleans are accepted as well as strings, so
+/// that e.g. `floor: 2` does not need quoting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabelValue(pub String);
+
+impl<'de> Deserialize<'de> for LabelValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct LabelValueVisitor;
+
+        impl Visitor<'_> for LabelValueVisitor {
+            type Value = LabelValue;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a string, number or boolean")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                Ok(LabelValue(v.to_string()))
+            }
+
+            fn visit_bool<E: de::Error>(self, v: bool) -> Result<Self::Value, E> {
+                Ok(LabelValue(v.to_string()))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                Ok(LabelValue(v.to_string()))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                Ok(LabelValue(v.to_string()))
+            }
+
+            fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+                Ok(LabelValue(v.to_string()))
+            }
+        }
+
+        deserializer.deserialize_any(LabelValueVisitor)
+    }
+}
+
+/// Mapping file entries can be either a plain name or a map of settings.
+struct RawTagEntry(TagEntry);
+
+impl<'de> Deserialize<'de> for RawTagEntry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TagEntryVisitor;
+
+        impl<'de> Visitor<'de> for TagEntryVisitor {
+            type Value = TagEntry;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a tag name or a map of tag settings")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                Ok(TagEntry {
+                    name: Some(v.to_string()),
+                    ..TagEntry::default()
+                })
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                TagEntry::deserialize(MapAccessDeserializer::new(map))
+            }
+        }
+
+        deserializer
+            .deserialize_any(TagEntryVisitor)
+            .map(RawTagEntry)
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum MappingError {
+    #[error("{0:?} is not a valid MAC address")]
+    InvalidMac(String),
+    #[error("{first:?} and {second:?} are the same MAC address")]
+    DuplicateMac { first: String, second: String },
+    #[error("Tag {mac}: {label:?} is not a valid label name")]
+    InvalidLabelName { mac: MacAddress, label: String },
+    #[error("Tag {mac}: label {label:?} is reserved by the exporter")]
+    ReservedLabel { mac: MacAddress, label: String },
+    #[error("Tag {mac}: calibration {field} is not a valid correction")]
+    InvalidCalibration {
+        mac: MacAddress,
+        field: &'static str,
+    },
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(try_from = "BTreeMap<String, RawTagEntry>")]
 pub struct MacMapping {
-    #[serde(default, flatten)]
-    names: HashMap<String, String>,
+    entries: HashMap<MacAddress, TagEntry>,
+}
+
+impl TryFrom<BTreeMap<String, RawTagEntry>> for MacMapping {
+    type Error = MappingError;
+
+    fn try_from(raw: BTreeMap<String, RawTagEntry>) -> Result<Self, Self::Error> {
+        let mut entries = HashMap::new();
+        let mut spellings: HashMap<MacAddress, String> = HashMap::new();
+        for (key, RawTagEntry(entry)) in raw {
+            let mac: MacAddress = key
+                .parse()
+                .map_err(|_| MappingError::InvalidMac(key.clone()))?;
+            if let Some(first) = spellings.insert(mac, key.clone()) {
+                return Err(MappingError::DuplicateMac { first, second: key });
+            }
+            entries.insert(mac, entry);
+        }
+
+        let mapping = Self { entries };
+        mapping.validate()?;
+        Ok(mapping)
+    }
 }
 
 impl MacMapping {
-    pub fn lookup(&self, mac: &str) -> Option<&str> {
-        self.names.get(mac).map(String::as_str)
+    pub fn lookup(&self, mac: &MacAddress) -> Option<&str> {
+        self.entries.get(mac)?.name.as_deref()
+    }
+
+    /// Labels of the tag besides its name, see `TagEntry::extra_labels`.
+    pub fn extra_labels(&self, mac: &MacAddress) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .get(mac)
+            .into_iter()
+            .flat_map(TagEntry::extra_labels)
+    }
+
+    /// Returns the timeout of the tag, falling back to `default` if the tag has no override.
+    pub fn tag_timeout(&self, mac: &MacAddress, default: Option<Duration>) -> Option<Duration> {
+        self.entries
+            .get(mac)
+            .and_then(|entry| entry.timeout)
+            .map(seconds)
+            .or(default)
+    }
+
+    /// Returns the corrections of the tag's readings, which are empty for unmapped tags.
+    pub fn calibration(&self, mac: &MacAddress) -> Calibration {
+        self.entries
+            .get(mac)
+            .map(|entry| entry.calibration)
+            .unwrap_or_default()
     }
 
     pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
@@ -39,6 +514,25 @@ impl MacMapping {
         let reader = BufReader::new(file);
         Ok(serde_yaml::from_reader(reader)?)
     }
+
+    /// Checks that the extra labels and the calibration of every entry can be used.
+    fn validate(&self) -> Result<(), MappingError> {
+        let mut entries: Vec<_> = self.entries.iter().collect();
+        entries.sort_by_key(|(mac, _)| *mac);
+        match entries
+            .into_iter()
+            .find_map(|(mac, entry)| entry.problems(*mac).into_iter().next())
+        {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Converts whole seconds from the configuration into a `Duration`.
+#[allow(clippy::cast_precision_loss)]
+pub fn seconds(seconds: u64) -> Duration {
+    Duration::from_seconds(seconds as f64)
 }
 
 #[cfg(test)]
@@ -47,25 +541,66 @@ mod tests {
     use std::io::Write;
     use tempfile::NamedTempFile;
 
+    fn mac(s: &str) -> MacAddress {
+        s.parse().unwrap()
+    }
+
     fn create_temp_config(content: &str) -> NamedTempFile {
         let mut file = NamedTempFile::new().unwrap();
         writeln!(file, "{}", content).unwrap();
         file
     }
 
+    /// Resolves the configuration from `args` with the given environment variables.
+    fn resolve(args: &[&str], env: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let cli =
+            Cli::try_parse_from(std::iter::once("program").chain(args.iter().copied())).unwrap();
+        Config::resolve(cli, |key| env.get(key).cloned())
+    }
+
     #[test]
     fn test_default_config() {
-        let config = Config::try_parse_from(["program"]).unwrap();
+        let config = resolve(&[], &[]).unwrap();
         assert_eq!(config.port, 9000);
-        assert_eq!(config.interface, "0.0.0.0");
+        assert_eq!(config.interface, IpAddr::from([0, 0, 0, 0]));
         assert!(config.mac_mapping.is_none());
+        assert!(config.tags.is_none());
+        assert!(config.tag_timeout.is_none());
+        assert_eq!(config.ready_window, 300);
+        assert!(!config.sample_timestamps);
+    }
+
+    #[test]
+    fn test_tag_timeout() {
+        let config = resolve(&["--tag-timeout", "300"], &[]).unwrap();
+        assert_eq!(config.tag_timeout, Some(300));
+    }
+
+    #[test]
+    fn test_sample_timestamps() {
+        let config = resolve(&["--sample-timestamps"], &[]).unwrap();
+        assert!(config.sample_timestamps);
+    }
+
+    #[test]
+    fn test_raw_metrics() {
+        assert!(!resolve(&[], &[]).unwrap().raw_metrics);
+        assert!(resolve(&["--raw-metrics"], &[]).unwrap().raw_metrics);
+        let env = [("RUUVI_EXPORTER_RAW_METRICS", "true")];
+        assert!(resolve(&[], &env).unwrap().raw_metrics);
+        // The command line can turn it off again
+        assert!(!resolve(&["--raw-metrics=false"], &env).unwrap().raw_metrics);
     }
 
     #[test]
     fn test_custom_port_and_interface() {
-        let config = Config::try_parse_from(["program", "-p", "8080", "-i", "127.0.0.1"]).unwrap();
+        let config = resolve(&["-p", "8080", "-i", "127.0.0.1"], &[]).unwrap();
         assert_eq!(config.port, 8080);
-        assert_eq!(config.interface, "127.0.0.1");
+        assert_eq!(config.interface, IpAddr::from([127, 0, 0, 1]));
     }
 
     #[test]
@@ -75,17 +610,19 @@ mod tests {
             "11:22:33:44:55:66": "Kitchen"
         "#;
         let mac_mapping_path = create_temp_config(mac_mapping_content);
-        let config = Config::try_parse_from([
-            "program",
-            "--mac-mapping",
-            mac_mapping_path.path().to_str().unwrap(),
-        ])
+        let config = resolve(
+            &["--mac-mapping", mac_mapping_path.path().to_str().unwrap()],
+            &[],
+        )
         .unwrap();
 
         let mapping = MacMapping::load(config.mac_mapping.unwrap()).unwrap();
-        assert_eq!(mapping.lookup("AA:BB:CC:DD:EE:FF"), Some("Living Room"));
-        assert_eq!(mapping.lookup("11:22:33:44:55:66"), Some("Kitchen"));
-        assert_eq!(mapping.lookup("00:00:00:00:00:00"), None);
+        assert_eq!(
+            mapping.lookup(&mac("AA:BB:CC:DD:EE:FF")),
+            Some("Living Room")
+        );
+        assert_eq!(mapping.lookup(&mac("11:22:33:44:55:66")), Some("Kitchen"));
+        assert_eq!(mapping.lookup(&mac("00:00:00:00:00:00")), None);
     }
 
     #[test]
@@ -97,9 +634,12 @@ mod tests {
         let mac_mapping_path = create_temp_config(mac_mapping_content);
 
         let mapping = MacMapping::load(mac_mapping_path).unwrap();
-        assert_eq!(mapping.lookup("AA:BB:CC:DD:EE:FF"), Some("Living Room"));
-        assert_eq!(mapping.lookup("11:22:33:44:55:66"), Some("Kitchen"));
-        assert_eq!(mapping.lookup("00:00:00:00:00:00"), None);
+        assert_eq!(
+            mapping.lookup(&mac("AA:BB:CC:DD:EE:FF")),
+            Some("Living Room")
+        );
+        assert_eq!(mapping.lookup(&mac("11:22:33:44:55:66")), Some("Kitchen"));
+        assert_eq!(mapping.lookup(&mac("00:00:00:00:00:00")), None);
     }
 
     #[test]
@@ -117,6 +657,395 @@ mod tests {
         let mac_mapping_path = create_temp_config(mac_mapping_content);
 
         let mapping = MacMapping::load(mac_mapping_path).unwrap();
-        assert_eq!(mapping.lookup("any-mac"), None);
+        assert_eq!(mapping.lookup(&mac("00:00:00:00:00:00")), None);
+    }
+
+    #[test]
+    fn test_mac_mapping_with_tag_settings() {
+        let mac_mapping_content = r#"
+            "AA:BB:CC:DD:EE:FF": "Living Room"
+            "11:22:33:44:55:66":
+                name: "Freezer"
+                timeout: 900
+            "22:33:44:55:66:77":
+                timeout: 60
+        "#;
+        let mac_mapping_path = create_temp_config(mac_mapping_content);
+
+        let mapping = MacMapping::load(mac_mapping_path).unwrap();
+        let default = Some(seconds(300));
+        assert_eq!(
+            mapping.lookup(&mac("AA:BB:CC:DD:EE:FF")),
+            Some("Living Room")
+        );
+        assert_eq!(mapping.lookup(&mac("11:22:33:44:55:66")), Some("Freezer"));
+        assert_eq!(mapping.lookup(&mac("22:33:44:55:66:77")), None);
+        assert_eq!(
+            mapping.tag_timeout(&mac("AA:BB:CC:DD:EE:FF"), default),
+            default
+        );
+        assert_eq!(
+            mapping.tag_timeout(&mac("11:22:33:44:55:66"), default),
+            Some(seconds(900))
+        );
+        assert_eq!(
+            mapping.tag_timeout(&mac("22:33:44:55:66:77"), None),
+            Some(seconds(60))
+        );
+        assert_eq!(mapping.tag_timeout(&mac("00:00:00:00:00:00"), None), None);
+    }
+
+    #[test]
+    fn test_mac_mapping_unknown_tag_setting() {
+        let mac_mapping_content = r#"
+            "AA:BB:CC:DD:EE:FF":
+                nmae: "Living Room"
+        "#;
+        let mac_mapping_path = create_temp_config(mac_mapping_content);
+
+        assert!(MacMapping::load(mac_mapping_path).is_err());
+    }
+
+    #[test]
+    fn test_mac_mapping_with_metadata() {
+        let mac_mapping_content = r#"
+            "AA:BB:CC:DD:EE:FF": "Living Room"
+            "11:22:33:44:55:66":
+                name: "Freezer"
+                location: "Kitchen"
+                floor: 2
+                building: "Main house"
+                labels:
+                    zone: "cold"
+                    critical: true
+        "#;
+        let mac_mapping_path = create_temp_config(mac_mapping_content);
+
+        let mapping = MacMapping::load(mac_mapping_path).unwrap();
+        assert_eq!(mapping.lookup(&mac("11:22:33:44:55:66")), Some("Freezer"));
+        assert_eq!(
+            mapping
+                .extra_labels(&mac("11:22:33:44:55:66"))
+                .collect::<Vec<_>>(),
+            [
+                ("location", "Kitchen"),
+                ("floor", "2"),
+                ("building", "Main house"),
+                ("critical", "true"),
+                ("zone", "cold"),
+            ]
+        );
+        assert_eq!(mapping.extra_labels(&mac("AA:BB:CC:DD:EE:FF")).count(), 0);
+        assert_eq!(mapping.extra_labels(&mac("00:00:00:00:00:00")).count(), 0);
+    }
+
+    #[test]
+    fn test_mac_mapping_invalid_label_names() {
+        let invalid = create_temp_config(
+            r#"
+            "AA:BB:CC:DD:EE:FF":
+                labels:
+                    "room-type": "bedroom"
+            "#,
+        );
+        let err = MacMapping::load(invalid).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"Tag AA:BB:CC:DD:EE:FF: "room-type" is not a valid label name"#
+        );
+
+        let reserved = create_temp_config(
+            r#"
+            "AA:BB:CC:DD:EE:FF":
+                labels:
+                    gw_mac: "11:22:33:44:55:66"
+            "#,
+        );
+        let err = MacMapping::load(reserved).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"Tag AA:BB:CC:DD:EE:FF: label "gw_mac" is reserved by the exporter"#
+        );
+    }
+
+    #[test]
+    fn test_mac_mapping_calibration() {
+        let mapping = create_temp_config(
+            r#"
+            "AA:BB:CC:DD:EE:FF":
+                name: "Sauna"
+                calibration:
+                    temperature_offset: -0.5
+                    humidity_scale: 1.05
+                    co2_offset: 40
+            "#,
+        );
+        let mapping = MacMapping::load(mapping).unwrap();
+        assert_eq!(
+            mapping.calibration(&mac("AA:BB:CC:DD:EE:FF")),
+            Calibration {
+                temperature_offset: Some(-0.5),
+                humidity_scale: Some(1.05),
+                co2_offset: Some(40),
+                ..Calibration::default()
+            }
+        );
+        assert!(mapping.calibration(&mac("00:00:00:00:00:00")).is_empty());
+
+        let invalid = create_temp_config(
+            r#"
+            "AA:BB:CC:DD:EE:FF":
+                calibration:
+                    humidity_scale: 0
+            "#,
+        );
+        assert_eq!(
+            MacMapping::load(invalid).unwrap_err().to_string(),
+            "Tag AA:BB:CC:DD:EE:FF: calibration humidity_scale is not a valid correction"
+        );
+    }
+
+    #[test]
+    fn test_mac_mapping_normalises_macs() {
+        let mac_mapping_content = r#"
+            "dd:19:92:cb:60:21": "Bedroom"
+            "DE4FBC29ECB5": "Office"
+            "e1-67-4c-f5-77-29": "Bathroom"
+        "#;
+        let mac_mapping_path = create_temp_config(mac_mapping_content);
+
+        let mapping = MacMapping::load(mac_mapping_path).unwrap();
+        assert_eq!(mapping.lookup(&mac("DD:19:92:CB:60:21")), Some("Bedroom"));
+        assert_eq!(mapping.lookup(&mac("DE:4F:BC:29:EC:B5")), Some("Office"));
+        assert_eq!(mapping.lookup(&mac("E1:67:4C:F5:77:29")), Some("Bathroom"));
+    }
+
+    #[test]
+    fn test_mac_mapping_rejects_invalid_macs() {
+        let invalid = create_temp_config(r#""Living Room": "AA:BB:CC:DD:EE:FF""#);
+        let err = MacMapping::load(invalid).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with(r#""Living Room" is not a valid MAC address"#));
+
+        let duplicate = create_temp_config(
+            r#"
+            "AA:BB:CC:DD:EE:FF": "Living Room"
+            "aa:bb:cc:dd:ee:ff": "Kitchen"
+            "#,
+        );
+        let err = MacMapping::load(duplicate).unwrap_err();
+        assert!(err.to_string().starts_with(
+            r#""AA:BB:CC:DD:EE:FF" and "aa:bb:cc:dd:ee:ff" are the same MAC address"#
+        ));
+    }
+
+    #[test]
+    fn test_config_file() {
+        let config_file = create_temp_config(
+            r#"
+            port: 9100
+            interface: "127.0.0.1"
+            tag_timeout: 600
+            sample_timestamps: true
+            tags:
+                "AA:BB:CC:DD:EE:FF": "Living Room"
+            ingest_auth:
+                tokens: ["s3cret"]
+            allowed_gateways: ["aa:bb:cc:dd:ee:ff"]
+            "#,
+        );
+        let config = resolve(&["--config", config_file.path().to_str().unwrap()], &[]).unwrap();
+        assert_eq!(config.port, 9100);
+        assert_eq!(config.interface, IpAddr::from([127, 0, 0, 1]));
+        assert_eq!(config.tag_timeout, Some(600));
+        assert!(config.sample_timestamps);
+        assert!(config.mac_mapping.is_none());
+        assert_eq!(config.ingest_auth.tokens.len(), 1);
+        assert_eq!(
+            config.allowed_gateways,
+            Some(vec![mac("AA:BB:CC:DD:EE:FF")])
+        );
+        assert!(config.allowed_tags.is_none());
+        assert_eq!(
+            config.tags.unwrap().lookup(&mac("AA:BB:CC:DD:EE:FF")),
+            Some("Living Room")
+        );
+    }
+
+    #[test]
+    fn test_config_precedence() {
+        let config_file = create_temp_config(
+            r#"
+            port: 9100
+            interface: "127.0.0.1"
+            tag_timeout: 600
+            ready_window: 120
+            "#,
+        );
+        let path = config_file.path().to_str().unwrap();
+
+        // File values override the defaults
+        let config = resolve(&[], &[("RUUVI_EXPORTER_CONFIG", path)]).unwrap();
+        assert_eq!(config.port, 9100);
+        assert_eq!(config.interface, IpAddr::from([127, 0, 0, 1]));
+        assert_eq!(config.tag_timeout, Some(600));
+        assert_eq!(config.ready_window, 120);
+        assert!(!config.sample_timestamps);
+
+        // Environment variables override the file
+        let env = [
+            ("RUUVI_EXPORTER_CONFIG", path),
+            ("RUUVI_EXPORTER_PORT", "9200"),
+            ("RUUVI_EXPORTER_TAG_TIMEOUT", "60"),
+            ("RUUVI_EXPORTER_READY_WINDOW", "90"),
+            ("RUUVI_EXPORTER_SAMPLE_TIMESTAMPS", "true"),
+        ];
+        let config = resolve(&[], &env).unwrap();
+        assert_eq!(config.ready_window, 90);
+        assert_eq!(config.port, 9200);
+        assert_eq!(config.interface, IpAddr::from([127, 0, 0, 1]));
+        assert_eq!(config.tag_timeout, Some(60));
+        assert!(config.sample_timestamps);
+
+        // Command line options override the environment
+        let config = resolve(&["-p", "9300", "-i", "::1"], &env).unwrap();
+        assert_eq!(config.port, 9300);
+        assert_eq!(config.interface, "::1".parse::<IpAddr>().unwrap());
+        assert_eq!(config.tag_timeout, Some(60));
+        assert!(config.sample_timestamps);
+        let config = resolve(&["--sample-timestamps=false"], &env).unwrap();
+        assert!(!config.sample_timestamps);
+    }
+
+    #[test]
+    fn test_config_file_mapping_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.yaml");
+        std::fs::write(&config_path, "mac_mapping: tags.yaml\n").unwrap();
+        let config_path = config_path.to_str().unwrap();
+
+        // Relative mapping paths are resolved against the config file's directory
+        let config = resolve(&["-c", config_path], &[]).unwrap();
+        assert_eq!(config.mac_mapping, Some(dir.path().join("tags.yaml")));
+
+        // A mapping file given on the command line replaces the one in the config file
+        let config = resolve(&["-c", config_path, "-m", "/etc/tags.yaml"], &[]).unwrap();
+        assert_eq!(config.mac_mapping, Some(PathBuf::from("/etc/tags.yaml")));
+
+        let conflict = create_temp_config(
+            r#"
+            mac_mapping: tags.yaml
+            tags:
+                "AA:BB:CC:DD:EE:FF": "Living Room"
+            "#,
+        );
+        let result = resolve(&["-c", conflict.path().to_str().unwrap()], &[]);
+        assert!(matches!(result, Err(ConfigError::MappingConflict(_))));
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let unknown = create_temp_config("prot: 9100");
+        let result = resolve(&["-c", unknown.path().to_str().unwrap()], &[]);
+        assert!(matches!(result, Err(ConfigError::Parse(_, _))));
+
+        let result = resolve(&[], &[("RUUVI_EXPORTER_PORT", "http")]);
+        assert!(matches!(
+            result,
+            Err(ConfigError::Env { name, .. }) if name == "RUUVI_EXPORTER_PORT"
+        ));
+        // Invalid environment variables are ignored when the command line takes precedence
+        assert!(resolve(&["-p", "9000"], &[("RUUVI_EXPORTER_PORT", "http")]).is_ok());
+
+        let result = resolve(&["-c", "/nonexistent/config.yaml"], &[]);
+        assert!(matches!(result, Err(ConfigError::Io(_, _))));
+    }
+
+    #[test]
+    fn test_tls_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.yaml");
+        std::fs::write(&config_path, "tls:\n  cert: cert.pem\n  key: key.pem\n").unwrap();
+        let config_path = config_path.to_str().unwrap();
+
+        let config = resolve(&["-c", config_path], &[]).unwrap();
+        assert_eq!(
+            config.tls,
+            Some(TlsConfig {
+                cert: dir.path().join("cert.pem"),
+                key: dir.path().join("key.pem"),
+            })
+        );
+
+        let config = resolve(
+            &["-c", config_path, "--tls-cert", "/etc/tls/cert.pem"],
+            &[("RUUVI_EXPORTER_TLS_KEY", "/etc/tls/key.pem")],
+        )
+        .unwrap();
+        assert_eq!(
+            config.tls,
+            Some(TlsConfig {
+                cert: PathBuf::from("/etc/tls/cert.pem"),
+                key: PathBuf::from("/etc/tls/key.pem"),
+            })
+        );
+
+        assert!(resolve(&[], &[]).unwrap().tls.is_none());
+        assert!(matches!(
+            resolve(&["--tls-cert", "cert.pem"], &[]),
+            Err(ConfigError::IncompleteTls)
+        ));
+    }
+
+    #[test]
+    fn test_log_config() {
+        let config = resolve(&[], &[]).unwrap();
+        assert_eq!(config.log_level, LogLevel::default());
+        assert_eq!(config.log_format, LogFormat::Text);
+
+        let config_file = create_temp_config("log_level: warn\nlog_format: json");
+        let path = config_file.path().to_str().unwrap();
+        let config = resolve(&["-c", path], &[]).unwrap();
+        assert_eq!(config.log_level, "warn".parse().unwrap());
+        assert_eq!(config.log_format, LogFormat::Json);
+
+        let config = resolve(
+            &["-c", path, "--log-level", "debug"],
+            &[("RUUVI_EXPORTER_LOG_FORMAT", "text")],
+        )
+        .unwrap();
+        assert_eq!(config.log_level, "debug".parse().unwrap());
+        assert_eq!(config.log_format, LogFormat::Text);
+
+        let result = resolve(&[], &[("RUUVI_EXPORTER_LOG_LEVEL", "loud")]);
+        assert!(matches!(result, Err(ConfigError::Env { .. })));
+    }
+
+    #[test]
+    fn test_derived_metrics() {
+        use crate::psychrometrics::DerivedMetric;
+
+        let config = resolve(&[], &[]).unwrap();
+        assert_eq!(config.derived_metrics, DerivedMetrics::default());
+
+        let config_file = create_temp_config("derived_metrics: [dew_point, absolute_humidity]");
+        let path = config_file.path().to_str().unwrap();
+        let config = resolve(&["-c", path], &[]).unwrap();
+        assert!(config.derived_metrics.is_enabled(DerivedMetric::DewPoint));
+        assert!(!config.derived_metrics.is_enabled(DerivedMetric::FrostPoint));
+
+        let config = resolve(
+            &["-c", path],
+            &[("RUUVI_EXPORTER_DERIVED_METRICS", "frost_point")],
+        )
+        .unwrap();
+        assert!(config.derived_metrics.is_enabled(DerivedMetric::FrostPoint));
+        assert!(!config.derived_metrics.is_enabled(DerivedMetric::DewPoint));
+
+        let config = resolve(&["--derived-metrics", "all"], &[]).unwrap();
+        assert!(config
+            .derived_metrics
+            .is_enabled(DerivedMetric::MixingRatio));
     }
 }
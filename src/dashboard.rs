@@ -0,0 +1,206 @@
+use std::fmt::Write;
+
+use crate::api::{TagList, TagReport};
+use crate::health::HealthReport;
+
+/// Seconds between automatic reloads of the page.
+const REFRESH_INTERVAL: u32 = 10;
+
+const STYLE: &str = "\
+body { font-family: sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; margin-bottom: 2em; }
+th, td { padding: 0.3em 0.8em; border-bottom: 1px solid #ddd; text-align: left; }
+td.number { text-align: right; font-variant-numeric: tabular-nums; }
+tr.stale { background: #fde2e1; color: #8a1c14; }
+.mac { font-family: monospace; color: #666; }";
+
+/// Escapes text for use in HTML content and attribute values.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Formats a value of `tag` scaled by `scale`, or a dash if the tag does not report it.
+fn value(tag: &TagReport, field: &str, scale: f64, precision: usize, unit: &str) -> String {
+    match tag.values.get(field) {
+        Some(reading) => format!("{:.precision$} {unit}", reading.value * scale),
+        None => "–".to_string(),
+    }
+}
+
+fn name_cell(name: Option<&str>, mac: &str) -> String {
+    match name {
+        Some(name) => format!(
+            "<td>{} <span class=\"mac\">{}</span></td>",
+            escape(name),
+            escape(mac)
+        ),
+        None => format!("<td><span class=\"mac\">{}</span></td>", escape(mac)),
+    }
+}
+
+/// Renders the status page. `now` is the current time in Unix seconds.
+///
+/// Gateways that have not posted within the readiness window are highlighted, as are tags
+/// that are down or have not been heard within it.
+pub fn render(health: &HealthReport, tags: &TagList, now: f64) -> String {
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta http-equiv=\"refresh\" content=\"{REFRESH_INTERVAL}\">\n\
+         <title>Ruuvi Gateway Exporter</title>\n<style>\n{STYLE}\n</style>\n</head>\n<body>\n"
+    );
+
+    html.push_str("<h1>Gateways</h1>\n");
+    if health.gateways.is_empty() {
+        html.push_str("<p>No gateway has posted yet.</p>\n");
+    } else {
+        html.push_str("<table>\n<tr><th>Gateway</th><th>Last post</th></tr>\n");
+        for gateway in &health.gateways {
+            let class = if gateway.recent {
+                ""
+            } else {
+                " class=\"stale\""
+            };
+            let _ = writeln!(
+                html,
+                "<tr{class}>{}<td class=\"number\">{:.0} s ago</td></tr>",
+                name_cell(gateway.name.as_deref(), &gateway.gw_mac.to_string()),
+                gateway.seconds_since_post
+            );
+        }
+        html.push_str("</table>\n");
+    }
+
+    html.push_str("<h1>Tags</h1>\n");
+    if tags.tags.is_empty() {
+        html.push_str("<p>No tag has been heard yet.</p>\n");
+    } else {
+        html.push_str(
+            "<table>\n<tr><th>Tag</th><th>Temperature</th><th>Humidity</th><th>Pressure</th>\
+             <th>CO2</th><th>Battery</th><th>RSSI</th><th>Last seen</th></tr>\n",
+        );
+        for tag in &tags.tags {
+            let age = now - tag.last_received;
+            let stale = !tag.up || age > health.ready_window_seconds;
+            let class = if stale { " class=\"stale\"" } else { "" };
+            let _ = writeln!(
+                html,
+                "<tr{class}>{}<td class=\"number\">{}</td><td class=\"number\">{}</td>\
+                 <td class=\"number\">{}</td><td class=\"number\">{}</td>\
+                 <td class=\"number\">{}</td><td class=\"number\">{} dBm</td>\
+                 <td class=\"number\">{age:.0} s ago</td></tr>",
+                name_cell(tag.name.as_deref(), &tag.mac.to_string()),
+                value(tag, "temperature", 1.0, 1, "°C"),
+                value(tag, "humidity", 1.0, 0, "%"),
+                value(tag, "pressure", 0.01, 1, "hPa"),
+                value(tag, "co2", 1.0, 0, "ppm"),
+                value(tag, "battery_voltage", 1.0, 2, "V"),
+                tag.rssi,
+            );
+        }
+        html.push_str("</table>\n");
+    }
+
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::Reading;
+    use crate::health::GatewayHealth;
+    use std::collections::BTreeMap;
+
+    /// A tag reported by a gateway whose clock is a day behind.
+    fn tag(mac: &str, name: Option<&str>, last_received: f64, up: bool) -> TagReport {
+        TagReport {
+            mac: mac.parse().unwrap(),
+            name: name.map(str::to_string),
+            labels: BTreeMap::new(),
+            data_format: "5",
+            last_seen: last_received - 86_400.0,
+            last_received,
+            up,
+            rssi: -71,
+            best_gateway: "11:11:11:11:11:11".parse().unwrap(),
+            gateways: vec![],
+            values: BTreeMap::from([
+                (
+                    "temperature",
+                    Reading {
+                        value: 21.345,
+                        unit: Some("°C"),
+                    },
+                ),
+                (
+                    "pressure",
+                    Reading {
+                        value: 100_347.0,
+                        unit: Some("Pa"),
+                    },
+                ),
+            ]),
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let health = HealthReport {
+            ready: true,
+            ready_window_seconds: 300.0,
+            gateways: vec![GatewayHealth {
+                gw_mac: "11:11:11:11:11:11".parse().unwrap(),
+                name: Some("Attic".to_string()),
+                last_post: 990.0,
+                seconds_since_post: 10.0,
+                recent: true,
+            }],
+        };
+        let tags = TagList {
+            tags: vec![
+                tag("AA:AA:AA:AA:AA:AA", Some("<Kitchen & Bath>"), 995.0, true),
+                tag("BB:BB:BB:BB:BB:BB", None, 100.0, true),
+            ],
+        };
+        let html = render(&health, &tags, 1000.0);
+
+        assert!(html.contains("<tr><td>Attic <span class=\"mac\">11:11:11:11:11:11</span>"));
+        assert!(
+            html.contains("<tr><td>&lt;Kitchen &amp; Bath&gt; <span"),
+            "{html}"
+        );
+        assert!(html.contains("21.3 °C"));
+        assert!(html.contains("1003.5 hPa"));
+        assert!(html.contains("<td class=\"number\">–</td>"));
+        assert!(html.contains("-71 dBm"));
+        assert!(html.contains("5 s ago"));
+        // Not heard within the readiness window
+        assert!(html
+            .contains("<tr class=\"stale\"><td><span class=\"mac\">BB:BB:BB:BB:BB:BB</span></td>"));
+    }
+
+    #[test]
+    fn test_render_empty() {
+        let health = HealthReport {
+            ready: false,
+            ready_window_seconds: 300.0,
+            gateways: vec![],
+        };
+        let html = render(&health, &TagList { tags: vec![] }, 1000.0);
+        assert!(html.contains("No gateway has posted yet."));
+        assert!(html.contains("No tag has been heard yet."));
+    }
+}
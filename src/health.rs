@@ -0,0 +1,141 @@
+use hifitime::{Duration, Epoch};
+use serde::Serialize;
+
+use crate::config::MacMapping;
+use crate::mac::MacAddress;
+use crate::measurements::Measurements;
+
+/// Latest post of a single gateway.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GatewayHealth {
+    pub gw_mac: MacAddress,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Time the latest post was received, in Unix seconds
+    pub last_post: f64,
+    /// Seconds since the latest post
+    pub seconds_since_post: f64,
+    /// Whether the latest post is within the readiness window
+    pub recent: bool,
+}
+
+/// Body of the health and readiness endpoints.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HealthReport {
+    /// Whether at least one gateway has posted within the readiness window
+    pub ready: bool,
+    pub ready_window_seconds: f64,
+    /// Gateways sorted by MAC address
+    pub gateways: Vec<GatewayHealth>,
+}
+
+/// Reports the latest post of every gateway at `now`.
+pub fn report(
+    state: &Measurements,
+    names: &MacMapping,
+    now: Epoch,
+    ready_window: Duration,
+) -> HealthReport {
+    let mut gateways: Vec<_> = state
+        .gateways
+        .iter()
+        .map(|(gw_mac, gateway)| {
+            // Gateway clocks can be wrong, so only the exporter's receive time is trusted
+            let age = now - gateway.last_received;
+            GatewayHealth {
+                gw_mac: *gw_mac,
+                name: names.lookup(gw_mac).map(str::to_string),
+                last_post: gateway.last_received.to_unix_seconds(),
+                seconds_since_post: age.to_seconds(),
+                recent: age <= ready_window,
+            }
+        })
+        .collect();
+    gateways.sort_by_key(|gateway| gateway.gw_mac);
+
+    HealthReport {
+        ready: gateways.iter().any(|gateway| gateway.recent),
+        ready_window_seconds: ready_window.to_seconds(),
+        gateways,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::seconds;
+    use crate::rw_message::GwMessage;
+
+    /// Posts from `gw_mac`, whose clock says `timestamp`, received at `received`.
+    fn post(measurements: &mut Measurements, gw_mac: &str, timestamp: f64, received: f64) {
+        measurements.update_gateway(
+            &GwMessage {
+                coordinates: String::new(),
+                timestamp: Epoch::from_unix_seconds(timestamp),
+                nonce: 1,
+                gw_mac: gw_mac.parse().unwrap(),
+                tags: vec![],
+                invalid_tags: vec![],
+                invalid_macs: vec![],
+            },
+            Epoch::from_unix_seconds(received),
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let now = Epoch::from_unix_seconds(1_000_000.0);
+        let mut measurements = Measurements::new();
+        let names = MacMapping::default();
+
+        let health = report(&measurements, &names, now, seconds(300));
+        assert!(!health.ready);
+        assert!(health.gateways.is_empty());
+
+        post(&mut measurements, "BB:BB:BB:BB:BB:BB", 999_940.0, 999_940.0);
+        post(&mut measurements, "AA:AA:AA:AA:AA:AA", 996_400.0, 996_400.0);
+        let health = report(&measurements, &names, now, seconds(300));
+        assert!(health.ready);
+        assert_eq!(health.ready_window_seconds, 300.0);
+        assert_eq!(
+            health.gateways[0],
+            GatewayHealth {
+                gw_mac: "AA:AA:AA:AA:AA:AA".parse().unwrap(),
+                name: None,
+                last_post: 1_000_000.0 - 3600.0,
+                seconds_since_post: 3600.0,
+                recent: false,
+            }
+        );
+        assert!(health.gateways[1].recent);
+
+        // Only stale gateways
+        let health = report(&measurements, &names, now, seconds(30));
+        assert!(!health.ready);
+    }
+
+    #[test]
+    fn test_report_ignores_gateway_clock() {
+        let now = Epoch::from_unix_seconds(1_000_000.0);
+        let names = MacMapping::default();
+
+        // A gateway that has not synced its clock yet posts just now
+        let mut measurements = Measurements::new();
+        post(&mut measurements, "AA:AA:AA:AA:AA:AA", 0.0, 999_990.0);
+        let health = report(&measurements, &names, now, seconds(300));
+        assert!(health.ready);
+        assert_eq!(health.gateways[0].last_post, 999_990.0);
+        assert_eq!(health.gateways[0].seconds_since_post, 10.0);
+
+        // A gateway whose clock runs an hour ahead posted long ago
+        let mut measurements = Measurements::new();
+        post(
+            &mut measurements,
+            "AA:AA:AA:AA:AA:AA",
+            1_003_000.0,
+            999_000.0,
+        );
+        let health = report(&measurements, &names, now, seconds(300));
+        assert!(!health.ready);
+    }
+}
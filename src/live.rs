@@ -0,0 +1,221 @@
+use futures_util::Stream;
+use hifitime::Epoch;
+use serde::Deserialize;
+use std::{collections::HashSet, convert::Infallible, sync::Arc};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::debug;
+use warp::sse::Event;
+
+use crate::api::{self, TagReport};
+use crate::collector::CollectorOptions;
+use crate::config::MacMapping;
+use crate::mac::{MacAddress, MacAddressParseError};
+use crate::measurements::Measurements;
+
+/// Number of updates buffered for each subscriber. Slower subscribers miss updates.
+const CHANNEL_CAPACITY: usize = 256;
+
+/// Fans out new tag readings to the subscribed clients.
+pub struct Updates {
+    sender: broadcast::Sender<Arc<TagReport>>,
+}
+
+impl Updates {
+    pub fn new() -> Self {
+        Self {
+            sender: broadcast::channel(CHANNEL_CAPACITY).0,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<TagReport>> {
+        self.sender.subscribe()
+    }
+
+    /// Sends the current reading of each of the tags `macs` to the subscribers.
+    pub fn publish(
+        &self,
+        state: &Measurements,
+        names: &MacMapping,
+        options: &CollectorOptions,
+        now: Epoch,
+        macs: &[MacAddress],
+    ) {
+        // Nobody to build the reports for
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        for &mac in macs {
+            if let Some(report) = api::tag(state, names, options, now, mac) {
+                let _ = self.sender.send(Arc::new(report));
+            }
+        }
+    }
+}
+
+/// Query parameters selecting the tags a client is interested in.
+#[derive(Debug, Default, Deserialize)]
+pub struct TagQuery {
+    /// Comma separated tag MAC addresses
+    pub mac: Option<String>,
+    /// Comma separated mapped tag names
+    pub name: Option<String>,
+}
+
+/// Selects tags by MAC address or mapped name. An empty filter selects all tags.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TagFilter {
+    pub macs: HashSet<MacAddress>,
+    pub names: HashSet<String>,
+}
+
+impl TagFilter {
+    pub fn is_empty(&self) -> bool {
+        self.macs.is_empty() && self.names.is_empty()
+    }
+
+    pub fn matches(&self, report: &TagReport) -> bool {
+        self.is_empty()
+            || self.macs.contains(&report.mac)
+            || report
+                .name
+                .as_ref()
+                .is_some_and(|name| self.names.contains(name))
+    }
+}
+
+impl TryFrom<TagQuery> for TagFilter {
+    type Error = MacAddressParseError;
+
+    fn try_from(query: TagQuery) -> Result<Self, Self::Error> {
+        let split = |list: Option<String>| -> Vec<String> {
+            list.iter()
+                .flat_map(|list| list.split(','))
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+        Ok(Self {
+            macs: split(query.mac)
+                .iter()
+                .map(|mac| mac.parse())
+                .collect::<Result<_, _>>()?,
+            names: split(query.name).into_iter().collect(),
+        })
+    }
+}
+
+/// Turns the updates received by `receiver` into server-sent events, one `tag` event per
+/// reading that passes `filter`.
+pub fn events(
+    receiver: broadcast::Receiver<Arc<TagReport>>,
+    filter: TagFilter,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    futures_util::stream::unfold((receiver, filter), |(mut receiver, filter)| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(report) if filter.matches(&report) => {
+                    let event = Event::default()
+                        .event("tag")
+                        .json_data(&*report)
+                        .expect("Tag reports serialize to JSON");
+                    return Some((Ok(event), (receiver, filter)));
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => {
+                    debug!("Event stream subscriber missed {missed} updates");
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rw_message::TagMessage;
+    use futures_util::StreamExt;
+
+    fn mac(s: &str) -> MacAddress {
+        s.parse().unwrap()
+    }
+
+    fn filter(mac: Option<&str>, name: Option<&str>) -> Result<TagFilter, MacAddressParseError> {
+        TagFilter::try_from(TagQuery {
+            mac: mac.map(str::to_string),
+            name: name.map(str::to_string),
+        })
+    }
+
+    #[test]
+    fn test_tag_filter() {
+        let report = |mac_address: &str, name: Option<&str>| TagReport {
+            mac: mac(mac_address),
+            name: name.map(str::to_string),
+            labels: Default::default(),
+            data_format: "5",
+            last_seen: 1000.0,
+            last_received: 1000.0,
+            up: true,
+            rssi: -60,
+            best_gateway: mac("11:11:11:11:11:11"),
+            gateways: vec![],
+            values: Default::default(),
+        };
+        let kitchen = report("AA:AA:AA:AA:AA:AA", Some("Kitchen"));
+        let unnamed = report("BB:BB:BB:BB:BB:BB", None);
+
+        let all = filter(None, None).unwrap();
+        assert!(all.matches(&kitchen) && all.matches(&unnamed));
+
+        let by_mac = filter(Some("bb-bb-bb-bb-bb-bb, CC:CC:CC:CC:CC:CC"), None).unwrap();
+        assert!(!by_mac.matches(&kitchen));
+        assert!(by_mac.matches(&unnamed));
+
+        let by_name = filter(Some(""), Some("Kitchen,Sauna")).unwrap();
+        assert!(by_name.matches(&kitchen));
+        assert!(!by_name.matches(&unnamed));
+
+        assert!(filter(Some("kitchen"), None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        let updates = Updates::new();
+        let receiver = updates.subscribe();
+        let mut events = Box::pin(events(
+            receiver,
+            filter(Some("DD:19:92:CB:60:21"), None).unwrap(),
+        ));
+
+        let mut state = Measurements::new();
+        let data = "0201061BFF9904050FE0337CC4ABFC1400340024A5B6EBA544DD1992CB6021";
+        for tag_mac in ["AA:AA:AA:AA:AA:AA", "DD:19:92:CB:60:21"] {
+            state.update_tag(
+                mac("11:11:11:11:11:11"),
+                &TagMessage {
+                    mac: mac(tag_mac),
+                    data: hex::decode(data).unwrap(),
+                    timestamp: Epoch::from_unix_seconds(1000.0),
+                    rssi: -60,
+                },
+                Epoch::from_unix_seconds(1000.0),
+            );
+        }
+        updates.publish(
+            &state,
+            &MacMapping::default(),
+            &CollectorOptions::default(),
+            Epoch::from_unix_seconds(1000.0),
+            &[mac("AA:AA:AA:AA:AA:AA"), mac("DD:19:92:CB:60:21")],
+        );
+        drop(updates);
+
+        // Only the selected tag is sent, and the stream ends with the channel
+        let event = events.next().await.unwrap().unwrap().to_string();
+        assert!(event.starts_with("event:tag\ndata:{"), "{event}");
+        assert!(event.contains(r#""mac":"DD:19:92:CB:60:21""#), "{event}");
+        assert!(events.next().await.is_none());
+    }
+}
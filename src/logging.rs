@@ -0,0 +1,162 @@
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::Hash,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::EnvFilter;
+
+/// Output format of the log.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("expected text or json, got {s:?}")),
+        }
+    }
+}
+
+/// Most verbose level of messages that are logged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogLevel(pub LevelFilter);
+
+impl Default for LogLevel {
+    fn default() -> Self {
+        LogLevel(LevelFilter::INFO)
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse()
+            .map(LogLevel)
+            .map_err(|_| format!("expected off, error, warn, info, debug or trace, got {s:?}"))
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<'de> Deserialize<'de> for LogLevel {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Installs the global logger. Libraries are limited to warnings unless `level` is stricter.
+pub fn init(level: LogLevel, format: LogFormat) {
+    let libraries = level.0.min(LevelFilter::WARN);
+    let filter = EnvFilter::new(format!(
+        "{libraries},{}={}",
+        env!("CARGO_CRATE_NAME"),
+        level
+    ));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
+/// Limits how often a repeated warning about the same subject is logged.
+#[derive(Debug)]
+pub struct RateLimiter<K> {
+    interval: Duration,
+    /// Time the warning was last logged and the number of times it was suppressed since
+    last: HashMap<K, (Instant, u64)>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    /// Number of subjects after which forgotten subjects are cleaned up.
+    const CLEANUP_THRESHOLD: usize = 1024;
+
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: HashMap::new(),
+        }
+    }
+
+    /// Returns `Some` with the number of suppressed repetitions if the warning about `key`
+    /// should be logged at `now`, and `None` if it should be suppressed.
+    pub fn check(&mut self, key: K, now: Instant) -> Option<u64> {
+        if self.last.len() >= Self::CLEANUP_THRESHOLD {
+            let interval = self.interval;
+            self.last
+                .retain(|_, (logged, _)| now.duration_since(*logged) < interval);
+        }
+
+        match self.last.get_mut(&key) {
+            Some((logged, suppressed)) if now.duration_since(*logged) < self.interval => {
+                *suppressed += 1;
+                None
+            }
+            Some((logged, suppressed)) => {
+                *logged = now;
+                Some(std::mem::take(suppressed))
+            }
+            None => {
+                self.last.insert(key, (now, 0));
+                Some(0)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(Duration::from_secs(60));
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+
+        assert_eq!(limiter.check("a", at(0)), Some(0));
+        assert_eq!(limiter.check("a", at(10)), None);
+        assert_eq!(limiter.check("a", at(59)), None);
+        // Other subjects are limited separately
+        assert_eq!(limiter.check("b", at(30)), Some(0));
+        assert_eq!(limiter.check("a", at(60)), Some(2));
+        assert_eq!(limiter.check("a", at(61)), None);
+        assert_eq!(limiter.check("b", at(200)), Some(0));
+    }
+
+    #[test]
+    fn test_parse_options() {
+        assert_eq!("json".parse(), Ok(LogFormat::Json));
+        assert!("yaml".parse::<LogFormat>().is_err());
+        assert_eq!("debug".parse(), Ok(LogLevel(LevelFilter::DEBUG)));
+        assert_eq!("WARN".parse(), Ok(LogLevel(LevelFilter::WARN)));
+        assert!("verbose".parse::<LogLevel>().is_err());
+        assert_eq!(
+            serde_yaml::from_str::<LogLevel>("trace").unwrap(),
+            LogLevel(LevelFilter::TRACE)
+        );
+    }
+}
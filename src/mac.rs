@@ -0,0 +1,102 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, str::FromStr};
+use thiserror::Error;
+
+/// A Bluetooth or Ethernet MAC address.
+///
+/// Parsing accepts upper and lower case hex digits, either without separators
+/// (`DD1992CB6021`), separated by colons or dashes (`dd:19:92:cb:60:21`) or in dotted groups of
+/// four (`dd19.92cb.6021`). Addresses are always displayed as upper case and colon separated,
+/// which is also the form the Ruuvi Gateway uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MacAddress([u8; 6]);
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("Invalid MAC address {0:?}")]
+pub struct MacAddressParseError(String);
+
+impl FromStr for MacAddress {
+    type Err = MacAddressParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || MacAddressParseError(s.to_string());
+
+        let digits: String = match s.len() {
+            12 => s.to_string(),
+            14 if s.as_bytes()[4] == b'.' && s.as_bytes()[9] == b'.' => s.replace('.', ""),
+            17 => {
+                let separator = s.as_bytes()[2];
+                if separator != b':' && separator != b'-' {
+                    return Err(err());
+                }
+                let groups: Vec<&str> = s.split(char::from(separator)).collect();
+                if groups.len() != 6 || groups.iter().any(|group| group.len() != 2) {
+                    return Err(err());
+                }
+                groups.concat()
+            }
+            _ => return Err(err()),
+        };
+
+        let mut bytes = [0; 6];
+        hex::decode_to_slice(digits, &mut bytes).map_err(|_| err())?;
+        Ok(MacAddress(bytes))
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02X}:{b:02X}:{c:02X}:{d:02X}:{e:02X}:{g:02X}")
+    }
+}
+
+impl Serialize for MacAddress {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for MacAddress {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_formats() {
+        let expected = MacAddress([0xDD, 0x19, 0x92, 0xCB, 0x60, 0x21]);
+        for s in [
+            "DD:19:92:CB:60:21",
+            "dd:19:92:cb:60:21",
+            "DD-19-92-CB-60-21",
+            "DD1992CB6021",
+            "dd1992cb6021",
+            "dd19.92cb.6021",
+        ] {
+            assert_eq!(s.parse::<MacAddress>(), Ok(expected), "{s}");
+        }
+        assert_eq!(expected.to_string(), "DD:19:92:CB:60:21");
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for s in [
+            "",
+            "DD:19:92:CB:60",
+            "DD:19:92:CB:60:2G",
+            "DD:19-92:CB:60:21",
+            "DDD:19:92:CB:6:21",
+            "DD 19 92 CB 60 21",
+            "DD1992CB602",
+            "any-mac",
+        ] {
+            assert!(s.parse::<MacAddress>().is_err(), "{s}");
+        }
+    }
+}
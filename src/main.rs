@@ -1,82 +1,424 @@
 use clap::Parser;
-use parking_lot::Mutex;
+use hifitime::{Duration, Epoch};
+use parking_lot::{Mutex, MutexGuard};
 use rw_message::GwMessage;
-use std::{net::IpAddr, sync::Arc};
-use warp::{reply::Reply, Filter};
+use std::{process::exit, sync::Arc, time::Instant};
+use tokio::net::TcpListener;
+use tracing::{error, info, warn};
+use warp::{
+    http::{
+        header::{CONTENT_TYPE, WWW_AUTHENTICATE},
+        StatusCode,
+    },
+    hyper::body::Bytes,
+    reply::{Reply, Response},
+    Filter,
+};
 
+mod allowlist;
+mod api;
+mod auth;
+mod calibration;
+mod check;
 mod collector;
 mod config;
+mod dashboard;
+mod health;
+mod live;
+mod logging;
+mod mac;
 mod measurements;
 mod metrics;
+mod psychrometrics;
+mod reload;
 mod rw_message;
+mod stats;
+mod tls;
+mod websocket;
 
-use collector::collect_metrics;
-use config::{Config, MacMapping};
+use allowlist::Allowlist;
+use auth::{IngestAuth, MetricsAuth};
+use collector::{collect_metrics, CollectorOptions};
+use config::{seconds, Cli, Command, Config, MacMapping};
+use health::HealthReport;
+use live::{TagFilter, TagQuery, Updates};
 use measurements::Measurements;
+use metrics::{Format, MetricsWriter};
+use reload::MappingStore;
+use stats::{IngestResult, IngestStats};
+use tls::CertStore;
 
+/// State shared by the request handlers.
+struct App {
+    sensor_state: Mutex<Measurements>,
+    mapping: Arc<MappingStore>,
+    options: CollectorOptions,
+    ingest_auth: IngestAuth,
+    metrics_auth: MetricsAuth,
+    allowlist: Allowlist,
+    ingest_stats: IngestStats,
+    /// How recently a gateway must have posted for the exporter to be ready
+    ready_window: Duration,
+    /// New tag readings for the live streams and websockets
+    updates: Updates,
+}
+
+impl App {
+    /// Locks the sensor state after removing the tags that have not been seen within their
+    /// timeout, so expiry does not depend on which endpoints are used.
+    fn state(&self, now: Epoch) -> MutexGuard<'_, Measurements> {
+        let names = self.mapping.get();
+        let mut state = self.sensor_state.lock();
+        state.remove_expired_tags(now, |mac| names.tag_timeout(mac, self.options.tag_timeout));
+        state
+    }
+}
+
+/// Reply asking the client to authenticate with the given `WWW-Authenticate` challenge.
+fn unauthorized(challenge: &'static str) -> Response {
+    let reply = warp::reply::with_status("", StatusCode::UNAUTHORIZED);
+    warp::reply::with_header(reply, WWW_AUTHENTICATE, challenge).into_response()
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn post_measurements(body: Bytes, authorization: Option<String>, app: Arc<App>) -> Response {
+    let started = Instant::now();
+    let (result, reply) = ingest(&body, authorization.as_deref(), &app);
+    app.ingest_stats
+        .record(result, body.len(), started.elapsed());
+    reply
+}
+
+fn ingest(body: &[u8], authorization: Option<&str>, app: &App) -> (IngestResult, Response) {
+    // Clients without any valid credential learn nothing about the expected body
+    if !app.ingest_auth.precheck(authorization) {
+        warn!("Rejected measurements: missing or invalid credentials");
+        return (
+            IngestResult::Unauthorized,
+            unauthorized(app.ingest_auth.challenge()),
+        );
+    }
+    let mut data: GwMessage = match serde_json::from_slice(body) {
+        Ok(data) => data,
+        Err(err) => {
+            warn!("Received an invalid measurement message: {err}");
+            let reply = warp::reply::with_status(err.to_string(), StatusCode::BAD_REQUEST);
+            return (IngestResult::Invalid, reply.into_response());
+        }
+    };
+
+    if !app.ingest_auth.authorize(authorization, data.gw_mac) {
+        warn!(
+            gateway = %data.gw_mac,
+            "Rejected measurements: missing or invalid credentials"
+        );
+        return (
+            IngestResult::Unauthorized,
+            unauthorized(app.ingest_auth.challenge()),
+        );
+    }
+    if !app.allowlist.allows_gateway(data.gw_mac) {
+        warn!(
+            gateway = %data.gw_mac,
+            "Rejected measurements: not in allowed_gateways"
+        );
+        let reply = warp::reply::with_status("", StatusCode::FORBIDDEN);
+        return (IngestResult::Forbidden, reply.into_response());
+    }
+    data.tags.retain(|tag| app.allowlist.allows_tag(tag.mac));
+    data.invalid_tags
+        .retain(|&mac| app.allowlist.allows_tag(mac));
+
+    let now = Epoch::now().expect("Failed to read system time");
+    let mut state = app.state(now);
+    let updated = state.update_gateway(&data, now);
+    app.updates
+        .publish(&state, &app.mapping.get(), &app.options, now, &updated);
+    drop(state);
+
+    let reply = warp::reply::with_header("", "X-Ruuvi-Gateway-Rate", "1");
+    (IngestResult::Accepted, reply.into_response())
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn metrics(accept: Option<String>, authorization: Option<String>, app: Arc<App>) -> Response {
+    if !app.metrics_auth.authorize(authorization.as_deref()) {
+        return unauthorized(app.metrics_auth.challenge());
+    }
+
+    let format = Format::negotiate(accept.as_deref());
+    let now = Epoch::now().expect("Failed to read system time");
+    let names = app.mapping.get();
+    let options = &app.options;
+    let mut metrics = MetricsWriter::new();
+
+    let state = app.state(now);
+    collect_metrics(&mut metrics, &state, &names, options, now);
+    state.decode_stats.collect_metrics(&mut metrics);
+    drop(state);
+
+    app.mapping.collect_metrics(&mut metrics);
+    app.ingest_auth.collect_metrics(&mut metrics);
+    app.metrics_auth.collect_metrics(&mut metrics);
+    app.allowlist.collect_metrics(&mut metrics);
+    app.ingest_stats.collect_metrics(&mut metrics);
+    stats::collect_build_info(&mut metrics);
+    warp::reply::with_header(metrics.render(format), CONTENT_TYPE, format.content_type())
+        .into_response()
+}
+
+/// Lists the current readings of all tags.
+#[allow(clippy::needless_pass_by_value)]
+fn api_tags(authorization: Option<String>, app: Arc<App>) -> Response {
+    if !app.metrics_auth.authorize(authorization.as_deref()) {
+        return unauthorized(app.metrics_auth.challenge());
+    }
+    let now = Epoch::now().expect("Failed to read system time");
+    let names = app.mapping.get();
+    let tags = api::tags(&app.state(now), &names, &app.options, now);
+    warp::reply::json(&tags).into_response()
+}
+
+/// Returns the current reading of a single tag.
+#[allow(clippy::needless_pass_by_value)]
+fn api_tag(mac: String, authorization: Option<String>, app: Arc<App>) -> Response {
+    if !app.metrics_auth.authorize(authorization.as_deref()) {
+        return unauthorized(app.metrics_auth.challenge());
+    }
+    let Ok(mac) = mac.parse() else {
+        let reply = warp::reply::with_status(
+            format!("{mac:?} is not a valid MAC address"),
+            StatusCode::BAD_REQUEST,
+        );
+        return reply.into_response();
+    };
+    let now = Epoch::now().expect("Failed to read system time");
+    let names = app.mapping.get();
+    match api::tag(&app.state(now), &names, &app.options, now, mac) {
+        Some(tag) => warp::reply::json(&tag).into_response(),
+        None => warp::reply::with_status("", StatusCode::NOT_FOUND).into_response(),
+    }
+}
+
+/// Streams new tag readings as server-sent events.
+#[allow(clippy::needless_pass_by_value)]
+fn api_stream(query: TagQuery, authorization: Option<String>, app: Arc<App>) -> Response {
+    if !app.metrics_auth.authorize(authorization.as_deref()) {
+        return unauthorized(app.metrics_auth.challenge());
+    }
+    let filter = match TagFilter::try_from(query) {
+        Ok(filter) => filter,
+        Err(err) => {
+            return warp::reply::with_status(err.to_string(), StatusCode::BAD_REQUEST)
+                .into_response();
+        }
+    };
+    let events = live::events(app.updates.subscribe(), filter);
+    warp::sse::reply(warp::sse::keep_alive().stream(events)).into_response()
+}
+
+/// Upgrades to a websocket sending tag snapshots and deltas, see `websocket::Session`.
+#[allow(clippy::needless_pass_by_value)]
+fn api_ws(ws: warp::ws::Ws, authorization: Option<String>, app: Arc<App>) -> Response {
+    if !app.metrics_auth.authorize(authorization.as_deref()) {
+        return unauthorized(app.metrics_auth.challenge());
+    }
+    let updates = app.updates.subscribe();
+    ws.on_upgrade(move |socket| {
+        websocket::serve(socket, updates, move || {
+            let now = Epoch::now().expect("Failed to read system time");
+            let names = app.mapping.get();
+            api::tags(&app.state(now), &names, &app.options, now).tags
+        })
+    })
+    .into_response()
+}
+
+/// Serves the HTML status page.
 #[allow(clippy::needless_pass_by_value)]
-fn post_measurements(
-    data: GwMessage,
-    sensor_state: Arc<parking_lot::lock_api::Mutex<parking_lot::RawMutex, Measurements>>,
-) -> impl Reply {
-    let mut state = sensor_state.lock();
-    state.last_update = data.timestamp;
-    state.last_nonce = Some(data.nonce);
-    state.mac = data.gw_mac;
-    for tag in &data.tags {
-        state.update_tag(tag);
+fn dashboard(authorization: Option<String>, app: Arc<App>) -> Response {
+    if !app.metrics_auth.authorize(authorization.as_deref()) {
+        return unauthorized(app.metrics_auth.challenge());
     }
+    let now = Epoch::now().expect("Failed to read system time");
+    let names = app.mapping.get();
+    let state = app.state(now);
+    let health = health::report(&state, &names, now, app.ready_window);
+    let tags = api::tags(&state, &names, &app.options, now);
     drop(state);
+    warp::reply::html(dashboard::render(&health, &tags, now.to_unix_seconds())).into_response()
+}
 
-    warp::reply::with_header("", "X-Ruuvi-Gateway-Rate", "1")
+fn health_report(app: &App) -> HealthReport {
+    let now = Epoch::now().expect("Failed to read system time");
+    let names = app.mapping.get();
+    health::report(&app.state(now), &names, now, app.ready_window)
 }
 
+/// Liveness probe, answers as long as the server is running.
 #[allow(clippy::needless_pass_by_value)]
-fn metrics(
-    sensor_state: Arc<parking_lot::lock_api::Mutex<parking_lot::RawMutex, Measurements>>,
-    names: Arc<MacMapping>,
-) -> impl Reply {
-    let state = sensor_state.lock();
-    collect_metrics(&state, &names)
+fn healthz(app: Arc<App>) -> Response {
+    warp::reply::json(&health_report(&app)).into_response()
+}
+
+/// Readiness probe, fails until a gateway has posted within the readiness window.
+#[allow(clippy::needless_pass_by_value)]
+fn ready(app: Arc<App>) -> Response {
+    let report = health_report(&app);
+    let status = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    warp::reply::with_status(warp::reply::json(&report), status).into_response()
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
-    let config = Config::parse();
+    let cli = Cli::parse();
+    if let Some(Command::CheckConfig) = cli.command {
+        exit(check::run(cli));
+    }
 
-    // Load MAC address mappings if config file is specified, otherwise use empty mapping
-    let names = config.mac_mapping.map_or_else(
-        MacMapping::default,
-        |path| MacMapping::load(&path).expect("Failed to load MAC mapping file"),
-    );
-    let names = Arc::new(names);
+    let mut config = match Config::resolve(cli, |key| std::env::var(key).ok()) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Error: {err}");
+            eprintln!("Run the check-config subcommand to list all problems");
+            exit(1);
+        }
+    };
+    logging::init(config.log_level, config.log_format);
 
-    let sensor_state = Arc::new(Mutex::new(Measurements::new()));
+    // Load MAC address mappings if a mapping file is specified, otherwise use the inline
+    // mapping of the config file or an empty mapping
+    let names = match &config.mac_mapping {
+        Some(path) => match MacMapping::load(path) {
+            Ok(names) => names,
+            Err(err) => {
+                error!("Failed to load MAC mapping file {path:?}: {err}");
+                info!("Run the check-config subcommand to list all problems");
+                exit(1);
+            }
+        },
+        None => config.tags.take().unwrap_or_default(),
+    };
+    let mapping = Arc::new(MappingStore::new(
+        config.mac_mapping.clone(),
+        names,
+        Epoch::now().expect("Failed to read system time"),
+    ));
+    reload::spawn_watchers(&mapping);
+
+    let app = Arc::new(App {
+        sensor_state: Mutex::new(Measurements::new()),
+        mapping,
+        options: CollectorOptions {
+            tag_timeout: config.tag_timeout.map(seconds),
+            sample_timestamps: config.sample_timestamps,
+            derived_metrics: config.derived_metrics,
+            raw_metrics: config.raw_metrics,
+        },
+        ingest_auth: IngestAuth::new(config.ingest_auth),
+        metrics_auth: MetricsAuth::new(config.metrics_auth),
+        allowlist: Allowlist::new(config.allowed_gateways, config.allowed_tags),
+        ingest_stats: IngestStats::new(),
+        ready_window: seconds(config.ready_window),
+        updates: Updates::new(),
+    });
+    let with_app = warp::any().map(move || app.clone());
 
     let post_measurements = warp::post()
         .and(warp::path::end())
         .and(warp::body::content_length_limit(1024 * 1024)) // 1 MB should be plenty for sensor data
-        .and(warp::body::json())
-        .and(warp::any().map({
-            let sensor_state = sensor_state.clone();
-            move || sensor_state.clone()
-        }))
+        .and(warp::body::bytes())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_app.clone())
         .map(post_measurements);
 
     let metrics = warp::get()
         .and(warp::path!("metrics"))
-        .and(warp::any().map({
-            let sensor_state = sensor_state.clone();
-            move || sensor_state.clone()
-        }))
-        .and(warp::any().map({
-            let names = names.clone();
-            move || names.clone()
-        }))
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_app.clone())
         .map(metrics);
 
-    println!("Starting server on {}:{}", config.interface, config.port);
-    warp::serve(post_measurements.or(metrics))
-        .run((config.interface.parse::<IpAddr>().unwrap(), config.port))
-        .await;
+    let dashboard = warp::get()
+        .and(warp::path::end())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_app.clone())
+        .map(dashboard);
+
+    let healthz = warp::get()
+        .and(warp::path!("healthz"))
+        .and(with_app.clone())
+        .map(healthz);
+    let ready = warp::get()
+        .and(warp::path!("ready"))
+        .and(with_app.clone())
+        .map(ready);
+
+    let api_tags = warp::get()
+        .and(warp::path!("api" / "v1" / "tags"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_app.clone())
+        .map(api_tags);
+    let api_tag = warp::get()
+        .and(warp::path!("api" / "v1" / "tags" / String))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_app.clone())
+        .map(api_tag);
+    let api_stream = warp::get()
+        .and(warp::path!("api" / "v1" / "stream"))
+        .and(warp::query::<TagQuery>())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_app.clone())
+        .map(api_stream);
+    let api_ws = warp::path!("api" / "v1" / "ws")
+        .and(warp::ws())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_app.clone())
+        .map(api_ws);
+
+    let routes = post_measurements
+        .or(metrics)
+        .or(dashboard)
+        .or(healthz)
+        .or(ready)
+        .or(api_tags)
+        .or(api_tag)
+        .or(api_stream)
+        .or(api_ws);
+
+    let address = (config.interface, config.port);
+    match config.tls {
+        Some(tls) => {
+            let certs = match CertStore::load(tls) {
+                Ok(certs) => Arc::new(certs),
+                Err(err) => {
+                    error!("{err}");
+                    exit(1);
+                }
+            };
+            tls::spawn_watchers(&certs);
+            let listener = match TcpListener::bind(address).await {
+                Ok(listener) => listener,
+                Err(err) => {
+                    error!("Failed to listen on {}:{}: {err}", address.0, address.1);
+                    exit(1);
+                }
+            };
+            info!(
+                "Starting HTTPS server on {}:{}",
+                config.interface, config.port
+            );
+            warp::serve(routes)
+                .run_incoming(tls::incoming(listener, certs.acceptor()))
+                .await;
+        }
+        None => {
+            info!("Starting server on {}:{}", config.interface, config.port);
+            warp::serve(routes).run(address).await;
+        }
+    }
 }
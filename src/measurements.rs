@@ -1,44 +1,262 @@
-use hifitime::Epoch;
+use hifitime::{Duration, Epoch};
 use ruuvi_decoders::RuuviData;
-use std::collections::HashMap;
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    time::{Duration as StdDuration, Instant},
+};
+use tracing::warn;
 
-use crate::rw_message::{AdMessageIter, TagMessage};
+use crate::logging::RateLimiter;
+use crate::mac::MacAddress;
+use crate::rw_message::{AdMessageIter, GwMessage, TagMessage};
+use crate::stats::{DecodeFailure, DecodeStats};
+
+/// A single gateway's view of a tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hearing {
+    /// Time of the observation, according to the gateway's clock
+    pub last_seen: Epoch,
+    /// Time the exporter received the observation
+    pub last_received: Epoch,
+    pub rssi: i32,
+}
 
 #[derive(Debug)]
 pub struct Tag {
+    /// Time of the current reading, according to the reporting gateway's clock
     pub last_seen: Epoch,
-    pub rssi: i32,
+    /// Time the exporter received the current reading
+    pub last_received: Epoch,
+    /// Gateway that delivered the current `values`
+    pub best_gateway: MacAddress,
+    /// Latest observation of the tag by each gateway that has heard it
+    pub heard_by: BTreeMap<MacAddress, Hearing>,
     pub values: RuuviData,
+    pub packets: PacketCounts,
 }
 
-pub struct Measurements {
+/// Readings of a tag counted by their measurement sequence numbers. Readings without a
+/// sequence number are not counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PacketCounts {
+    /// Distinct readings received
+    pub received: u64,
+    /// Sequence numbers skipped between consecutive received readings
+    pub missed: u64,
+}
+
+/// Highest rate in readings per second at which tags are expected to advance their sequence
+/// number, with some margin.
+const MAX_MEASUREMENT_RATE: f64 = 10.0;
+
+impl PacketCounts {
+    /// Counts a reading with the sequence number `new`, received `elapsed` after the reading
+    /// with the sequence number `old` which it is about to replace. A repeat of `old`, e.g.
+    /// heard by another gateway, is not counted again. A gap larger than the tag could have
+    /// produced in `elapsed` means its counter restarted, and is not counted as missed.
+    fn count(&mut self, old: Option<(u32, u32)>, new: Option<(u32, u32)>, elapsed: Duration) {
+        let Some((new, modulus)) = new else {
+            return;
+        };
+        match old {
+            Some((old, old_modulus)) if old_modulus == modulus => {
+                let advance = sequence_advance(old, new, modulus);
+                if advance > 0 {
+                    self.received += 1;
+                    let possible = elapsed.abs().to_seconds() * MAX_MEASUREMENT_RATE + 1.0;
+                    if f64::from(advance) <= possible {
+                        self.missed += u64::from(advance - 1);
+                    }
+                }
+            }
+            // First reading of the tag in this data format
+            _ => self.received += 1,
+        }
+    }
+}
+
+/// Liveness of a tag with respect to its timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TagStatus {
+    /// The tag has been heard within the timeout
+    Up,
+    /// The tag has not been heard within the timeout, but is still reported
+    Down,
+    /// The tag has been down for another timeout period and should be forgotten
+    Expired,
+}
+
+impl Tag {
+    /// Returns the liveness of the tag at `now`, judged by when the exporter received the
+    /// current reading. Tags without a timeout never go down.
+    pub fn status(&self, now: Epoch, timeout: Option<Duration>) -> TagStatus {
+        let Some(timeout) = timeout else {
+            return TagStatus::Up;
+        };
+        let age = now - self.last_received;
+        if age <= timeout {
+            TagStatus::Up
+        } else if age <= timeout * 2 {
+            TagStatus::Down
+        } else {
+            TagStatus::Expired
+        }
+    }
+
+    /// RSSI of the observation currently held in `values`.
+    pub fn rssi(&self) -> i32 {
+        self.heard_by[&self.best_gateway].rssi
+    }
+
+    /// Decides whether an observation from `gw_mac` should replace the current values.
+    ///
+    /// Readings received within `SEQUENCE_WINDOW_SECONDS` of each other are ordered by
+    /// measurement sequence number when both carry comparable ones, otherwise by timestamp, so
+    /// a tag whose counter restarted after a reboot is not stuck with its older reading. The
+    /// same reading heard by several gateways is attributed to the one with the strongest
+    /// signal, whatever the gateways' clocks say.
+    fn is_better(&self, gw_mac: MacAddress, hearing: Hearing, values: &RuuviData) -> bool {
+        let elapsed = (hearing.last_received - self.last_received).abs();
+        let ordering = match (sequence_number(&self.values), sequence_number(values)) {
+            (Some(old), Some(new))
+                if old.1 == new.1 && elapsed.to_seconds() <= SEQUENCE_WINDOW_SECONDS =>
+            {
+                compare_sequence(old, new)
+            }
+            _ => hearing.last_seen.cmp(&self.last_seen),
+        };
+
+        match ordering {
+            Ordering::Greater => true,
+            Ordering::Less => false,
+            Ordering::Equal => gw_mac == self.best_gateway || hearing.rssi > self.rssi(),
+        }
+    }
+}
+
+/// Longest time in seconds between receiving two readings for their sequence numbers to be
+/// compared. Tags broadcast every few seconds, so this spans many readings, while a counter
+/// restarted by a reboot holds up new readings for no longer than this.
+const SEQUENCE_WINDOW_SECONDS: f64 = 60.0;
+
+/// Measurement sequence number of a reading and the number of values its counter cycles
+/// through.
+///
+/// Formats 5 and E1 reserve the all-ones value as "not available", so their counters wrap
+/// from 0xFFFE and 0xFFFFFE back to zero. Format 6 uses the whole byte.
+fn sequence_number(values: &RuuviData) -> Option<(u32, u32)> {
+    match values {
+        RuuviData::V5(data) => data
+            .measurement_sequence
+            .map(|seq| (u32::from(seq), 0xFFFF)),
+        RuuviData::V6(data) => data.measurement_sequence.map(|seq| (u32::from(seq), 0x100)),
+        RuuviData::E1(data) => data.measurement_sequence.map(|seq| (seq, 0xFF_FFFF)),
+    }
+}
+
+/// Number of steps the counter took from `old` to `new`, modulo `modulus`.
+fn sequence_advance(old: u32, new: u32, modulus: u32) -> u32 {
+    (new + modulus - old % modulus) % modulus
+}
+
+/// Compares two sequence numbers, treating the counter as wrapping.
+///
+/// `new` is considered greater if it is at most half the counter range ahead of `old`.
+fn compare_sequence((old, old_modulus): (u32, u32), (new, new_modulus): (u32, u32)) -> Ordering {
+    if old_modulus != new_modulus {
+        // Tag changed data format, sequence numbers are not comparable
+        return Ordering::Equal;
+    }
+    match sequence_advance(old, new, new_modulus) {
+        0 => Ordering::Equal,
+        diff if diff <= (new_modulus - 1) / 2 => Ordering::Greater,
+        _ => Ordering::Less,
+    }
+}
+
+/// State of a single gateway.
+#[derive(Debug)]
+pub struct Gateway {
+    /// Timestamp of the latest post, according to the gateway's clock
     pub last_update: Epoch,
+    /// Time the exporter received the latest post
+    pub last_received: Epoch,
     pub last_nonce: Option<u64>,
-    pub mac: String,
-    pub tags: HashMap<String, Tag>,
 }
 
-impl Measurements {
+impl Gateway {
     pub fn new() -> Self {
         Self {
             last_update: hifitime::UNIX_REF_EPOCH, // Hopefully far enough in the history
+            last_received: hifitime::UNIX_REF_EPOCH,
             last_nonce: None,
-            mac: String::new(),
+        }
+    }
+}
+
+/// Minimum time between repeated warnings about the same tag failing to decode the same way.
+const DECODE_WARNING_INTERVAL: StdDuration = StdDuration::from_secs(300);
+
+/// Measurements of all gateways and the tags they have heard.
+pub struct Measurements {
+    /// Gateways keyed by gateway MAC
+    pub gateways: HashMap<MacAddress, Gateway>,
+    /// Tags keyed by tag MAC, merged over all gateways
+    pub tags: HashMap<MacAddress, Tag>,
+    pub decode_stats: DecodeStats,
+    decode_warnings: RateLimiter<(MacAddress, DecodeFailure)>,
+}
+
+impl Measurements {
+    pub fn new() -> Self {
+        Self {
+            gateways: HashMap::default(),
             tags: HashMap::default(),
+            decode_stats: DecodeStats::default(),
+            decode_warnings: RateLimiter::new(DECODE_WARNING_INTERVAL),
         }
     }
 
-    pub fn update_tag(&mut self, tag: &TagMessage) {
+    /// Updates the state of the gateway that sent `msg` at `received` and all tags it
+    /// reported. Returns the tags whose current reading changed.
+    pub fn update_gateway(&mut self, msg: &GwMessage, received: Epoch) -> Vec<MacAddress> {
+        let gateway = self.gateways.entry(msg.gw_mac).or_insert_with(Gateway::new);
+        gateway.last_update = msg.timestamp;
+        gateway.last_received = received;
+        gateway.last_nonce = Some(msg.nonce);
+
+        for &mac in &msg.invalid_tags {
+            self.record_failure(mac, msg.gw_mac, DecodeFailure::BadHex, None);
+        }
+        for key in &msg.invalid_macs {
+            self.record_invalid_mac(msg.gw_mac, key);
+        }
+        let mut updated = Vec::new();
+        for tag in &msg.tags {
+            if self.update_tag(msg.gw_mac, tag, received) {
+                updated.push(tag.mac);
+            }
+        }
+        updated
+    }
+
+    /// Decodes an advertisement of `tag` heard by `gw_mac` and received at `received`. Returns
+    /// whether a new reading was stored.
+    pub fn update_tag(&mut self, gw_mac: MacAddress, tag: &TagMessage, received: Epoch) -> bool {
         let msgs = AdMessageIter(&tag.data);
 
         // Find the last Ruuvi manufacturer-specific data (ad_type 0xff)
         // in case there are multiple advertisements
         let mut found_ruuvi = false;
-        for msg in msgs
-            .filter_map(Result::ok)
-            .filter(|msg| msg.ad_type == 0xff)
-        {
-            if msg.payload.len() < 2 {
+        let mut stored = false;
+        let mut truncated = false;
+        for msg in msgs {
+            let Ok(msg) = msg else {
+                truncated = true;
+                continue;
+            };
+            if msg.ad_type != 0xff || msg.payload.len() < 2 {
                 continue;
             }
             let (manufacturer_id, payload) = msg.payload.split_at(2);
@@ -47,30 +265,142 @@ impl Measurements {
             if manufacturer_id == 0x0499 {
                 found_ruuvi = true;
                 if let Ok(values) = RuuviData::decode(payload) {
-                    self.tags.insert(
-                        tag.name.clone(),
-                        Tag {
-                            last_seen: tag.timestamp,
-                            rssi: tag.rssi,
-                            values,
-                        },
-                    );
+                    self.decode_stats.record_decoded(&values);
+                    stored |= self.merge_tag(gw_mac, tag, received, values);
                 } else {
-                    eprintln!(
-                        "Warning: Could not parse Ruuvi data from tag {}: {}",
-                        tag.name,
-                        hex::encode_upper(&msg.payload)
+                    let payload = hex::encode_upper(&msg.payload);
+                    self.record_failure(
+                        tag.mac,
+                        gw_mac,
+                        DecodeFailure::DecodeError,
+                        Some(&payload),
                     );
                 }
             }
         }
 
-        if !found_ruuvi {
-            eprintln!(
-                "Warning: No Ruuvi manufacturer data found in advertisement from tag {}",
-                tag.name,
+        if found_ruuvi {
+            return stored;
+        }
+        let failure = if truncated {
+            DecodeFailure::Truncated
+        } else {
+            DecodeFailure::NoRuuviData
+        };
+        self.record_failure(
+            tag.mac,
+            gw_mac,
+            failure,
+            Some(&hex::encode_upper(&tag.data)),
+        );
+        false
+    }
+
+    /// Counts a decode failure and warns about it, unless the same tag has recently failed the
+    /// same way.
+    fn record_failure(
+        &mut self,
+        mac: MacAddress,
+        gw_mac: MacAddress,
+        failure: DecodeFailure,
+        data: Option<&str>,
+    ) {
+        self.decode_stats.record_failure(failure);
+        let Some(suppressed) = self.decode_warnings.check((mac, failure), Instant::now()) else {
+            return;
+        };
+        let message = match failure {
+            DecodeFailure::BadHex => "Advertisement data is not valid hex",
+            DecodeFailure::BadMac => unreachable!("Recorded by record_invalid_mac"),
+            DecodeFailure::Truncated => "Truncated advertisement",
+            DecodeFailure::NoRuuviData => "No Ruuvi manufacturer data found in advertisement",
+            DecodeFailure::DecodeError => "Could not parse Ruuvi data",
+        };
+        warn!(tag = %mac, gateway = %gw_mac, data, suppressed, "{message}");
+    }
+
+    /// Counts a tag reported under a key that is not a MAC address and warns about it, unless
+    /// the same gateway has recently sent one.
+    fn record_invalid_mac(&mut self, gw_mac: MacAddress, key: &str) {
+        let failure = DecodeFailure::BadMac;
+        self.decode_stats.record_failure(failure);
+        let Some(suppressed) = self
+            .decode_warnings
+            .check((gw_mac, failure), Instant::now())
+        else {
+            return;
+        };
+        warn!(gateway = %gw_mac, key, suppressed, "Tag key is not a valid MAC address");
+    }
+
+    /// Forgets expired tags, and gateways that have not heard a tag within its timeout.
+    pub fn remove_expired_tags(
+        &mut self,
+        now: Epoch,
+        timeout: impl Fn(&MacAddress) -> Option<Duration>,
+    ) {
+        self.tags.retain(|mac, tag| {
+            let Some(timeout) = timeout(mac) else {
+                return true;
+            };
+            let best_gateway = tag.best_gateway;
+            tag.heard_by.retain(|gw_mac, hearing| {
+                *gw_mac == best_gateway || now - hearing.last_received <= timeout
+            });
+            tag.status(now, Some(timeout)) != TagStatus::Expired
+        });
+    }
+
+    /// Merges a decoded observation of `tag` by `gw_mac` into the tag state. Returns whether it
+    /// replaced the current reading.
+    fn merge_tag(
+        &mut self,
+        gw_mac: MacAddress,
+        tag: &TagMessage,
+        received: Epoch,
+        values: RuuviData,
+    ) -> bool {
+        let hearing = Hearing {
+            last_seen: tag.timestamp,
+            last_received: received,
+            rssi: tag.rssi,
+        };
+
+        let Some(state) = self.tags.get_mut(&tag.mac) else {
+            let packets = PacketCounts {
+                received: u64::from(sequence_number(&values).is_some()),
+                missed: 0,
+            };
+            self.tags.insert(
+                tag.mac,
+                Tag {
+                    last_seen: tag.timestamp,
+                    last_received: received,
+                    best_gateway: gw_mac,
+                    heard_by: BTreeMap::from([(gw_mac, hearing)]),
+                    values,
+                    packets,
+                },
+            );
+            return true;
+        };
+
+        let is_better = state.is_better(gw_mac, hearing, &values);
+        state.heard_by.insert(gw_mac, hearing);
+        if is_better {
+            // Readings older than the current one are never better, so the counter only
+            // moves forward here
+            state.packets.count(
+                sequence_number(&state.values),
+                sequence_number(&values),
+                received - state.last_received,
             );
+            state.last_seen = tag.timestamp;
+            state.last_received = received;
+            state.best_gateway = gw_mac;
+            state.values = values;
         }
+        is_better
     }
 }
 
@@ -79,23 +409,33 @@ mod tests {
     use super::*;
     use hifitime::Epoch;
 
+    const GW_MAC: &str = "AA:AA:AA:AA:AA:AA";
+
+    fn mac(s: &str) -> MacAddress {
+        s.parse().unwrap()
+    }
+
+    fn at(seconds: f64) -> Epoch {
+        Epoch::from_unix_seconds(seconds)
+    }
+
     #[test]
     fn test_update_tag_with_standard_format() {
         // Standard format: ad_type 1 followed by ad_type 0xff
         let data =
             hex::decode("0201061BFF9904050FE0337CC4ABFC1400340024A5B6EBA544DD1992CB6021").unwrap();
         let tag = TagMessage {
-            name: "DD:19:92:CB:60:21".to_string(),
+            mac: mac("DD:19:92:CB:60:21"),
             data,
             timestamp: Epoch::from_unix_seconds(1736885086.0),
             rssi: -50,
         };
 
         let mut measurements = Measurements::new();
-        measurements.update_tag(&tag);
+        measurements.update_tag(mac(GW_MAC), &tag, tag.timestamp);
 
         assert_eq!(measurements.tags.len(), 1);
-        assert!(measurements.tags.contains_key("DD:19:92:CB:60:21"));
+        assert!(measurements.tags.contains_key(&mac("DD:19:92:CB:60:21")));
     }
 
     #[test]
@@ -105,21 +445,21 @@ mod tests {
             hex::decode("2BFF9904E110FE408CC53D000300060009000B02560D00FFFFFFFFFFFF001EBEB8FFFFFFFFFFF6BFB2EED156").unwrap();
 
         let tag = TagMessage {
-            name: "E1:67:4C:F5:77:29".to_string(),
+            mac: mac("E1:67:4C:F5:77:29"),
             data,
             timestamp: Epoch::from_unix_seconds(1736885086.0),
             rssi: -60,
         };
 
         let mut measurements = Measurements::new();
-        measurements.update_tag(&tag);
+        measurements.update_tag(mac(GW_MAC), &tag, tag.timestamp);
 
         // Tag should be added since E1 format is now supported
         assert_eq!(measurements.tags.len(), 1);
-        assert!(measurements.tags.contains_key("E1:67:4C:F5:77:29"));
+        assert!(measurements.tags.contains_key(&mac("E1:67:4C:F5:77:29")));
 
         // Verify it's E1 format
-        let tag = measurements.tags.get("E1:67:4C:F5:77:29").unwrap();
+        let tag = measurements.tags.get(&mac("E1:67:4C:F5:77:29")).unwrap();
         assert!(matches!(tag.values, RuuviData::E1(_)));
     }
 
@@ -128,16 +468,355 @@ mod tests {
         // Only ad_type 1, no manufacturer-specific data
         let data = hex::decode("020106").unwrap();
         let tag = TagMessage {
-            name: "AA:BB:CC:DD:EE:FF".to_string(),
+            mac: mac("AA:BB:CC:DD:EE:FF"),
             data,
             timestamp: Epoch::from_unix_seconds(1736885086.0),
             rssi: -50,
         };
 
         let mut measurements = Measurements::new();
-        measurements.update_tag(&tag);
+        measurements.update_tag(mac(GW_MAC), &tag, tag.timestamp);
 
         // Tag should not be added since there's no manufacturer data
         assert_eq!(measurements.tags.len(), 0);
+        assert_eq!(
+            measurements
+                .decode_stats
+                .failures(DecodeFailure::NoRuuviData),
+            1
+        );
+    }
+
+    #[test]
+    fn test_decode_failures_are_counted() {
+        let tag = |data: &str| TagMessage {
+            mac: mac("AA:BB:CC:DD:EE:FF"),
+            data: hex::decode(data).unwrap(),
+            timestamp: Epoch::from_unix_seconds(1736885086.0),
+            rssi: -50,
+        };
+        let mut measurements = Measurements::new();
+        // Length byte claims more data than there is
+        measurements.update_tag(mac(GW_MAC), &tag("0201061BFF9904050FE0"), at(1736885086.0));
+        // Ruuvi manufacturer data with an unknown data format
+        measurements.update_tag(
+            mac(GW_MAC),
+            &tag("0201060BFF9904FF0FE0337CC4ABFC"),
+            at(1736885086.0),
+        );
+        measurements.update_gateway(
+            &GwMessage {
+                coordinates: String::new(),
+                timestamp: Epoch::from_unix_seconds(1736885086.0),
+                nonce: 1,
+                gw_mac: mac(GW_MAC),
+                tags: vec![],
+                invalid_tags: vec![mac("AA:BB:CC:DD:EE:FF")],
+                invalid_macs: vec!["AA:BB:CC".to_string()],
+            },
+            Epoch::from_unix_seconds(1736885086.0),
+        );
+
+        let stats = &measurements.decode_stats;
+        assert!(measurements.tags.is_empty());
+        assert_eq!(stats.failures(DecodeFailure::Truncated), 1);
+        assert_eq!(stats.failures(DecodeFailure::DecodeError), 1);
+        assert_eq!(stats.failures(DecodeFailure::BadHex), 1);
+        assert_eq!(stats.failures(DecodeFailure::BadMac), 1);
+        assert_eq!(stats.failures(DecodeFailure::NoRuuviData), 0);
+    }
+
+    /// Builds a V5 advertisement with the given measurement sequence number.
+    fn v5_tag(timestamp: f64, sequence: u16, rssi: i32) -> TagMessage {
+        let mut data =
+            hex::decode("0201061BFF9904050FE0337CC4ABFC1400340024A5B6EBA544DD1992CB6021").unwrap();
+        data[23..25].copy_from_slice(&sequence.to_be_bytes());
+        TagMessage {
+            mac: mac("DD:19:92:CB:60:21"),
+            data,
+            timestamp: Epoch::from_unix_seconds(timestamp),
+            rssi,
+        }
+    }
+
+    fn sequence(measurements: &Measurements) -> Option<(u32, u32)> {
+        sequence_number(&measurements.tags[&mac("DD:19:92:CB:60:21")].values)
+    }
+
+    #[test]
+    fn test_gateways_are_tracked_separately() {
+        let msg = |gw_mac: &str, nonce| GwMessage {
+            coordinates: String::new(),
+            timestamp: Epoch::from_unix_seconds(1736885086.0),
+            nonce,
+            gw_mac: mac(gw_mac),
+            tags: vec![],
+            invalid_tags: vec![],
+            invalid_macs: vec![],
+        };
+
+        let mut measurements = Measurements::new();
+        measurements.update_gateway(
+            &msg("AA:AA:AA:AA:AA:AA", 1),
+            Epoch::from_unix_seconds(1736885086.0),
+        );
+        measurements.update_gateway(
+            &msg("BB:BB:BB:BB:BB:BB", 2),
+            Epoch::from_unix_seconds(1736885086.0),
+        );
+
+        assert_eq!(measurements.gateways.len(), 2);
+        assert_eq!(
+            measurements.gateways[&mac("AA:AA:AA:AA:AA:AA")].last_nonce,
+            Some(1)
+        );
+        assert_eq!(
+            measurements.gateways[&mac("BB:BB:BB:BB:BB:BB")].last_nonce,
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn test_same_tag_from_two_gateways_is_merged() {
+        let mut measurements = Measurements::new();
+        measurements.update_tag(mac("AA:AA:AA:AA:AA:AA"), &v5_tag(100.0, 10, -80), at(100.0));
+        measurements.update_tag(mac("BB:BB:BB:BB:BB:BB"), &v5_tag(100.0, 10, -60), at(100.0));
+
+        assert_eq!(measurements.tags.len(), 1);
+        let tag = &measurements.tags[&mac("DD:19:92:CB:60:21")];
+        // Same reading, the stronger signal wins
+        assert_eq!(tag.best_gateway, mac("BB:BB:BB:BB:BB:BB"));
+        assert_eq!(tag.rssi(), -60);
+        assert_eq!(tag.heard_by.len(), 2);
+        assert_eq!(tag.heard_by[&mac("AA:AA:AA:AA:AA:AA")].rssi, -80);
+    }
+
+    #[test]
+    fn test_same_reading_ignores_gateway_clocks() {
+        let mut measurements = Measurements::new();
+        measurements.update_tag(mac("AA:AA:AA:AA:AA:AA"), &v5_tag(100.0, 10, -60), at(100.0));
+        // The same reading stamped later by a gateway with a weaker signal
+        assert!(!measurements.update_tag(
+            mac("BB:BB:BB:BB:BB:BB"),
+            &v5_tag(102.0, 10, -80),
+            at(102.0)
+        ));
+        let tag = &measurements.tags[&mac("DD:19:92:CB:60:21")];
+        assert_eq!(tag.best_gateway, mac("AA:AA:AA:AA:AA:AA"));
+        assert_eq!(tag.last_seen, Epoch::from_unix_seconds(100.0));
+
+        // Stamped earlier, but with a stronger signal
+        assert!(measurements.update_tag(
+            mac("CC:CC:CC:CC:CC:CC"),
+            &v5_tag(99.0, 10, -40),
+            at(99.0)
+        ));
+        let tag = &measurements.tags[&mac("DD:19:92:CB:60:21")];
+        assert_eq!(tag.best_gateway, mac("CC:CC:CC:CC:CC:CC"));
+    }
+
+    #[test]
+    fn test_newer_sequence_number_wins() {
+        let mut measurements = Measurements::new();
+        assert!(measurements.update_tag(
+            mac("AA:AA:AA:AA:AA:AA"),
+            &v5_tag(100.0, 11, -80),
+            at(100.0)
+        ));
+        // Older reading with a stronger signal and a later gateway timestamp
+        assert!(!measurements.update_tag(
+            mac("BB:BB:BB:BB:BB:BB"),
+            &v5_tag(101.0, 10, -40),
+            at(101.0)
+        ));
+
+        let tag = &measurements.tags[&mac("DD:19:92:CB:60:21")];
+        assert_eq!(tag.best_gateway, mac("AA:AA:AA:AA:AA:AA"));
+        assert_eq!(sequence(&measurements), Some((11, 0xFFFF)));
+        assert_eq!(tag.heard_by[&mac("BB:BB:BB:BB:BB:BB")].rssi, -40);
+
+        measurements.update_tag(mac("BB:BB:BB:BB:BB:BB"), &v5_tag(102.0, 12, -40), at(102.0));
+        assert_eq!(
+            measurements.tags[&mac("DD:19:92:CB:60:21")].best_gateway,
+            mac("BB:BB:BB:BB:BB:BB")
+        );
+        assert_eq!(sequence(&measurements), Some((12, 0xFFFF)));
+    }
+
+    #[test]
+    fn test_sequence_number_reset() {
+        let mut measurements = Measurements::new();
+        measurements.update_tag(mac(GW_MAC), &v5_tag(100.0, 20000, -50), at(100.0));
+        // Shortly after, a lower counter is taken for a late copy of an older reading
+        assert!(!measurements.update_tag(mac(GW_MAC), &v5_tag(110.0, 5, -50), at(110.0)));
+        assert_eq!(sequence(&measurements), Some((20000, 0xFFFF)));
+
+        // Later, the tag has evidently rebooted and restarted its counter
+        assert!(measurements.update_tag(mac(GW_MAC), &v5_tag(1000.0, 5, -50), at(1000.0)));
+        assert_eq!(sequence(&measurements), Some((5, 0xFFFF)));
+        assert!(measurements.update_tag(mac(GW_MAC), &v5_tag(1001.0, 6, -50), at(1001.0)));
+        let tag = &measurements.tags[&mac("DD:19:92:CB:60:21")];
+        assert_eq!(tag.last_seen, at(1001.0));
+        assert_eq!(sequence(&measurements), Some((6, 0xFFFF)));
+    }
+
+    #[test]
+    fn test_sequence_number_wrap_around() {
+        let mut measurements = Measurements::new();
+        measurements.update_tag(mac(GW_MAC), &v5_tag(100.0, 65534, -50), at(100.0));
+        measurements.update_tag(mac(GW_MAC), &v5_tag(101.0, 1, -50), at(101.0));
+        assert_eq!(sequence(&measurements), Some((1, 0xFFFF)));
+
+        // A late copy of the reading before the wrap must not win
+        measurements.update_tag(
+            mac("BB:BB:BB:BB:BB:BB"),
+            &v5_tag(102.0, 65533, -30),
+            at(102.0),
+        );
+        assert_eq!(sequence(&measurements), Some((1, 0xFFFF)));
+    }
+
+    #[test]
+    fn test_packet_counts() {
+        let mut measurements = Measurements::new();
+        let packets = |measurements: &Measurements| {
+            let packets = measurements.tags[&mac("DD:19:92:CB:60:21")].packets;
+            (packets.received, packets.missed)
+        };
+        measurements.update_tag(mac(GW_MAC), &v5_tag(100.0, 65533, -50), at(100.0));
+        assert_eq!(packets(&measurements), (1, 0));
+        // The same reading relayed by another gateway
+        measurements.update_tag(
+            mac("BB:BB:BB:BB:BB:BB"),
+            &v5_tag(100.0, 65533, -30),
+            at(100.0),
+        );
+        measurements.update_tag(mac(GW_MAC), &v5_tag(101.0, 65534, -50), at(101.0));
+        assert_eq!(packets(&measurements), (2, 0));
+        // The counter wraps from 65534 to 0, so only 0 is missed
+        measurements.update_tag(mac(GW_MAC), &v5_tag(104.0, 1, -50), at(104.0));
+        assert_eq!(packets(&measurements), (3, 1));
+        // Late readings are not counted
+        measurements.update_tag(
+            mac("BB:BB:BB:BB:BB:BB"),
+            &v5_tag(105.0, 65534, -30),
+            at(105.0),
+        );
+        assert_eq!(packets(&measurements), (3, 1));
+
+        let mut counts = PacketCounts::default();
+        let elapsed = Duration::from_seconds(2.0);
+        counts.count(Some((250, 0x100)), Some((4, 0x100)), elapsed);
+        counts.count(Some((0xFF_FFFE, 0xFF_FFFF)), Some((3, 0xFF_FFFF)), elapsed);
+        assert_eq!(
+            counts,
+            PacketCounts {
+                received: 2,
+                missed: 12
+            }
+        );
+        // Not comparable after a change of data format
+        counts.count(Some((4, 0x100)), Some((100, 0xFF_FFFF)), elapsed);
+        counts.count(Some((100, 0xFF_FFFF)), None, elapsed);
+        assert_eq!(
+            counts,
+            PacketCounts {
+                received: 3,
+                missed: 12
+            }
+        );
+    }
+
+    #[test]
+    fn test_packet_counts_after_reset() {
+        let mut measurements = Measurements::new();
+        let packets = |measurements: &Measurements| {
+            let packets = measurements.tags[&mac("DD:19:92:CB:60:21")].packets;
+            (packets.received, packets.missed)
+        };
+        measurements.update_tag(mac(GW_MAC), &v5_tag(100.0, 40000, -50), at(100.0));
+        // The tag rebooted, the gap is far more than it could have sent in the meantime
+        measurements.update_tag(mac(GW_MAC), &v5_tag(200.0, 5, -50), at(200.0));
+        assert_eq!(packets(&measurements), (2, 0));
+        // Gaps that fit the elapsed time are still counted
+        measurements.update_tag(mac(GW_MAC), &v5_tag(202.0, 15, -50), at(202.0));
+        assert_eq!(packets(&measurements), (3, 9));
+    }
+
+    #[test]
+    fn test_compare_sequence() {
+        assert_eq!(
+            compare_sequence((5, 0xFFFF), (6, 0xFFFF)),
+            Ordering::Greater
+        );
+        assert_eq!(compare_sequence((6, 0xFFFF), (5, 0xFFFF)), Ordering::Less);
+        assert_eq!(compare_sequence((5, 0xFFFF), (5, 0xFFFF)), Ordering::Equal);
+        assert_eq!(
+            compare_sequence((0xFF_FFFE, 0xFF_FFFF), (0, 0xFF_FFFF)),
+            Ordering::Greater
+        );
+        assert_eq!(
+            compare_sequence((255, 0x100), (3, 0x100)),
+            Ordering::Greater
+        );
+        assert_eq!(compare_sequence((5, 0x100), (5, 0xFFFF)), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_tag_status() {
+        let mut measurements = Measurements::new();
+        measurements.update_tag(mac(GW_MAC), &v5_tag(100.0, 10, -50), at(100.0));
+        let tag = &measurements.tags[&mac("DD:19:92:CB:60:21")];
+        let timeout = Some(Duration::from_seconds(60.0));
+
+        assert_eq!(tag.status(at(1000.0), None), TagStatus::Up);
+        assert_eq!(tag.status(at(160.0), timeout), TagStatus::Up);
+        assert_eq!(tag.status(at(161.0), timeout), TagStatus::Down);
+        assert_eq!(tag.status(at(220.0), timeout), TagStatus::Down);
+        assert_eq!(tag.status(at(221.0), timeout), TagStatus::Expired);
+    }
+
+    #[test]
+    fn test_remove_expired_tags() {
+        let mut measurements = Measurements::new();
+        measurements.update_tag(mac(GW_MAC), &v5_tag(100.0, 10, -50), at(100.0));
+        measurements.update_tag(mac("BB:BB:BB:BB:BB:BB"), &v5_tag(100.0, 10, -70), at(100.0));
+        measurements.update_tag(mac(GW_MAC), &v5_tag(200.0, 11, -50), at(200.0));
+        let timeout = |_: &MacAddress| Some(Duration::from_seconds(60.0));
+
+        measurements.remove_expired_tags(Epoch::from_unix_seconds(210.0), timeout);
+        // The gateway that has not heard the tag within the timeout is forgotten
+        let tag = &measurements.tags[&mac("DD:19:92:CB:60:21")];
+        assert_eq!(tag.heard_by.keys().collect::<Vec<_>>(), [&mac(GW_MAC)]);
+
+        measurements.remove_expired_tags(Epoch::from_unix_seconds(320.0), timeout);
+        assert_eq!(measurements.tags.len(), 1);
+
+        measurements.remove_expired_tags(Epoch::from_unix_seconds(321.0), timeout);
+        assert!(measurements.tags.is_empty());
+    }
+
+    #[test]
+    fn test_expiry_ignores_gateway_clocks() {
+        // Both gateways' clocks are a day behind the exporter's
+        const DAY: f64 = 86_400.0;
+        let mut measurements = Measurements::new();
+        measurements.update_tag(mac(GW_MAC), &v5_tag(100.0 - DAY, 10, -50), at(100.0));
+        measurements.update_tag(
+            mac("BB:BB:BB:BB:BB:BB"),
+            &v5_tag(100.0 - DAY, 10, -70),
+            at(100.0),
+        );
+        let timeout = Duration::from_seconds(60.0);
+
+        let tag = &measurements.tags[&mac("DD:19:92:CB:60:21")];
+        assert_eq!(tag.last_seen, at(100.0 - DAY));
+        assert_eq!(tag.status(at(110.0), Some(timeout)), TagStatus::Up);
+        measurements.remove_expired_tags(at(110.0), |_| Some(timeout));
+        let tag = &measurements.tags[&mac("DD:19:92:CB:60:21")];
+        assert_eq!(tag.heard_by.len(), 2);
+
+        measurements.remove_expired_tags(at(221.0), |_| Some(timeout));
+        assert!(measurements.tags.is_empty());
     }
 }
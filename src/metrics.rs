@@ -1,4 +1,440 @@
-use std::fmt;
+use hifitime::Epoch;
+use std::fmt::{self, Write};
+
+/// Exposition format of the metrics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Prometheus text format 0.0.4
+    Prometheus,
+    /// OpenMetrics text format 1.0.0
+    OpenMetrics,
+}
+
+impl Format {
+    /// Picks the format preferred by the scraper's `Accept` header.
+    ///
+    /// Media ranges are weighted by their `q` parameter. Anything but an explicit preference
+    /// for OpenMetrics results in the Prometheus text format.
+    pub fn negotiate(accept: Option<&str>) -> Self {
+        let mut best = (Format::Prometheus, 0.0);
+        for media_range in accept.unwrap_or_default().split(',') {
+            let mut params = media_range.split(';').map(str::trim);
+            let format = match params.next() {
+                Some("application/openmetrics-text") => Format::OpenMetrics,
+                Some("text/plain") => Format::Prometheus,
+                _ => continue,
+            };
+            let q = params
+                .filter_map(|param| param.strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if q > best.1 {
+                best = (format, q);
+            }
+        }
+        best.0
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Prometheus => "text/plain; version=0.0.4; charset=utf-8",
+            Format::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        }
+    }
+}
+
+/// Prometheus metric type of a metric family.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricType {
+    Gauge,
+    Counter,
+    Histogram,
+}
+
+impl fmt::Display for MetricType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetricType::Gauge => write!(f, "gauge"),
+            MetricType::Counter => write!(f, "counter"),
+            MetricType::Histogram => write!(f, "histogram"),
+        }
+    }
+}
+
+/// Description of a metric family. Every exported sample belongs to one of these.
+#[derive(Debug)]
+pub struct MetricFamily {
+    pub name: &'static str,
+    pub metric_type: MetricType,
+    /// Unit of the samples, always also the suffix of `name`
+    pub unit: Option<&'static str>,
+    pub help: &'static str,
+    /// Counter exported under its bare name in the Prometheus format, as it was before counters
+    /// got the `_total` suffix
+    pub legacy_name: bool,
+}
+
+const fn family(
+    name: &'static str,
+    metric_type: MetricType,
+    unit: Option<&'static str>,
+    help: &'static str,
+) -> MetricFamily {
+    MetricFamily {
+        name,
+        metric_type,
+        unit,
+        help,
+        legacy_name: false,
+    }
+}
+
+impl MetricFamily {
+    /// Keeps the Prometheus format name of a counter that dashboards already rely on.
+    const fn with_legacy_name(mut self) -> Self {
+        self.legacy_name = true;
+        self
+    }
+}
+
+// Registry of all metric families exported by the exporter
+
+pub const GATEWAY_UPDATE_TIMESTAMP: MetricFamily = family(
+    "ruuvi_gateway_update_timestamp_seconds",
+    MetricType::Gauge,
+    Some("seconds"),
+    "Timestamp of the latest update posted by the gateway.",
+);
+pub const GATEWAY_NONCE: MetricFamily = family(
+    "ruuvi_gateway_nonce",
+    MetricType::Gauge,
+    None,
+    "Nonce of the latest update posted by the gateway.",
+);
+pub const TAG_LAST_SEEN_TIMESTAMP: MetricFamily = family(
+    "ruuvi_tag_last_seen_timestamp_seconds",
+    MetricType::Gauge,
+    Some("seconds"),
+    "Timestamp of the latest reading received from the tag.",
+);
+pub const TAG_UP: MetricFamily = family(
+    "ruuvi_tag_up",
+    MetricType::Gauge,
+    None,
+    "Whether the tag has been heard within its timeout.",
+);
+pub const TAG_SEQUENCE_NUMBER: MetricFamily = family(
+    "ruuvi_tag_sequence_number",
+    MetricType::Counter,
+    None,
+    "Measurement sequence number reported by the tag.",
+)
+.with_legacy_name();
+pub const TAG_PACKETS_RECEIVED: MetricFamily = family(
+    "ruuvi_tag_packets_received",
+    MetricType::Counter,
+    None,
+    "Distinct readings received from the tag, counted by measurement sequence number.",
+);
+pub const TAG_PACKETS_MISSED: MetricFamily = family(
+    "ruuvi_tag_packets_missed",
+    MetricType::Counter,
+    None,
+    "Measurement sequence numbers of the tag skipped between received readings.",
+);
+pub const TAG_TEMPERATURE: MetricFamily = family(
+    "ruuvi_tag_temperature_celsius",
+    MetricType::Gauge,
+    Some("celsius"),
+    "Temperature measured by the tag.",
+);
+pub const TAG_HUMIDITY: MetricFamily = family(
+    "ruuvi_tag_humidity_ratio",
+    MetricType::Gauge,
+    Some("ratio"),
+    "Relative humidity measured by the tag.",
+);
+pub const TAG_PRESSURE: MetricFamily = family(
+    "ruuvi_tag_pressure_pascals",
+    MetricType::Gauge,
+    Some("pascals"),
+    "Air pressure measured by the tag.",
+);
+pub const TAG_MOVEMENT_COUNTER: MetricFamily = family(
+    "ruuvi_tag_movement_counter",
+    MetricType::Counter,
+    None,
+    "Number of movements detected by the tag's accelerometer.",
+)
+.with_legacy_name();
+pub const TAG_ACCELERATION_X: MetricFamily = family(
+    "ruuvi_tag_acceleration_x_g",
+    MetricType::Gauge,
+    Some("g"),
+    "Acceleration along the X axis of the tag.",
+);
+pub const TAG_ACCELERATION_Y: MetricFamily = family(
+    "ruuvi_tag_acceleration_y_g",
+    MetricType::Gauge,
+    Some("g"),
+    "Acceleration along the Y axis of the tag.",
+);
+pub const TAG_ACCELERATION_Z: MetricFamily = family(
+    "ruuvi_tag_acceleration_z_g",
+    MetricType::Gauge,
+    Some("g"),
+    "Acceleration along the Z axis of the tag.",
+);
+pub const TAG_BATTERY: MetricFamily = family(
+    "ruuvi_tag_battery_volts",
+    MetricType::Gauge,
+    Some("volts"),
+    "Battery voltage of the tag.",
+);
+pub const TAG_TX_POWER: MetricFamily = family(
+    "ruuvi_tag_tx_power_dBm",
+    MetricType::Gauge,
+    Some("dBm"),
+    "Transmit power of the tag.",
+);
+pub const TAG_PM1_0: MetricFamily = family(
+    "ruuvi_tag_pm1_0_ugm3",
+    MetricType::Gauge,
+    Some("ugm3"),
+    "PM1.0 particulate matter concentration in µg/m³.",
+);
+pub const TAG_PM2_5: MetricFamily = family(
+    "ruuvi_tag_pm2_5_ugm3",
+    MetricType::Gauge,
+    Some("ugm3"),
+    "PM2.5 particulate matter concentration in µg/m³.",
+);
+pub const TAG_PM4_0: MetricFamily = family(
+    "ruuvi_tag_pm4_0_ugm3",
+    MetricType::Gauge,
+    Some("ugm3"),
+    "PM4.0 particulate matter concentration in µg/m³.",
+);
+pub const TAG_PM10_0: MetricFamily = family(
+    "ruuvi_tag_pm10_0_ugm3",
+    MetricType::Gauge,
+    Some("ugm3"),
+    "PM10 particulate matter concentration in µg/m³.",
+);
+pub const TAG_CO2: MetricFamily = family(
+    "ruuvi_tag_co2_ppm",
+    MetricType::Gauge,
+    Some("ppm"),
+    "CO2 concentration measured by the tag.",
+);
+pub const TAG_VOC_INDEX: MetricFamily = family(
+    "ruuvi_tag_voc_index",
+    MetricType::Gauge,
+    None,
+    "Volatile organic compounds index measured by the tag.",
+);
+pub const TAG_NOX_INDEX: MetricFamily = family(
+    "ruuvi_tag_nox_index",
+    MetricType::Gauge,
+    None,
+    "Nitrogen oxides index measured by the tag.",
+);
+pub const TAG_LUMINOSITY: MetricFamily = family(
+    "ruuvi_tag_luminosity_lux",
+    MetricType::Gauge,
+    Some("lux"),
+    "Illuminance measured by the tag.",
+);
+pub const TAG_DEW_POINT: MetricFamily = family(
+    "ruuvi_tag_dew_point_celsius",
+    MetricType::Gauge,
+    Some("celsius"),
+    "Dew point derived from the tag's temperature and humidity.",
+);
+pub const TAG_FROST_POINT: MetricFamily = family(
+    "ruuvi_tag_frost_point_celsius",
+    MetricType::Gauge,
+    Some("celsius"),
+    "Frost point derived from the tag's temperature and humidity.",
+);
+pub const TAG_ABSOLUTE_HUMIDITY: MetricFamily = family(
+    "ruuvi_tag_absolute_humidity_gm3",
+    MetricType::Gauge,
+    Some("gm3"),
+    "Water vapour in g/m³ derived from the tag's temperature and humidity.",
+);
+pub const TAG_MIXING_RATIO: MetricFamily = family(
+    "ruuvi_tag_mixing_ratio",
+    MetricType::Gauge,
+    Some("ratio"),
+    "Mass of water vapour per mass of dry air derived from the tag's measurements.",
+);
+pub const TAG_VAPOUR_PRESSURE_DEFICIT: MetricFamily = family(
+    "ruuvi_tag_vapour_pressure_deficit_pascals",
+    MetricType::Gauge,
+    Some("pascals"),
+    "Vapour pressure deficit derived from the tag's temperature and humidity.",
+);
+pub const TAG_TEMPERATURE_RAW: MetricFamily = family(
+    "ruuvi_tag_temperature_raw_celsius",
+    MetricType::Gauge,
+    Some("celsius"),
+    "Temperature measured by the tag before calibration.",
+);
+pub const TAG_HUMIDITY_RAW: MetricFamily = family(
+    "ruuvi_tag_humidity_raw_ratio",
+    MetricType::Gauge,
+    Some("ratio"),
+    "Relative humidity measured by the tag before calibration.",
+);
+pub const TAG_PRESSURE_RAW: MetricFamily = family(
+    "ruuvi_tag_pressure_raw_pascals",
+    MetricType::Gauge,
+    Some("pascals"),
+    "Air pressure measured by the tag before calibration.",
+);
+pub const TAG_CO2_RAW: MetricFamily = family(
+    "ruuvi_tag_co2_raw_ppm",
+    MetricType::Gauge,
+    Some("ppm"),
+    "CO2 concentration measured by the tag before calibration.",
+);
+pub const TAG_RSSI: MetricFamily = family(
+    "ruuvi_tag_rssi_dBm",
+    MetricType::Gauge,
+    Some("dBm"),
+    "Signal strength of the tag as received by the gateway.",
+);
+pub const TAG_BEST_GATEWAY: MetricFamily = family(
+    "ruuvi_tag_best_gateway",
+    MetricType::Gauge,
+    None,
+    "Gateway that delivered the tag's current reading.",
+);
+
+pub const CONFIG_LAST_RELOAD_SUCCESS: MetricFamily = family(
+    "ruuvi_exporter_config_last_reload_success",
+    MetricType::Gauge,
+    None,
+    "Whether the latest attempt to load the MAC mapping file succeeded.",
+);
+pub const CONFIG_LAST_RELOAD_SUCCESS_TIMESTAMP: MetricFamily = family(
+    "ruuvi_exporter_config_last_reload_success_timestamp_seconds",
+    MetricType::Gauge,
+    Some("seconds"),
+    "Timestamp of the latest successful load of the MAC mapping file.",
+);
+pub const INGEST_UNAUTHORIZED_REQUESTS: MetricFamily = family(
+    "ruuvi_exporter_ingest_unauthorized_requests",
+    MetricType::Counter,
+    None,
+    "Measurement posts rejected because of missing or invalid credentials.",
+);
+pub const METRICS_UNAUTHORIZED_REQUESTS: MetricFamily = family(
+    "ruuvi_exporter_metrics_unauthorized_requests",
+    MetricType::Counter,
+    None,
+    "Scrapes rejected because of missing or invalid credentials.",
+);
+pub const INGEST_DROPPED_MESSAGES: MetricFamily = family(
+    "ruuvi_exporter_ingest_dropped_messages",
+    MetricType::Counter,
+    None,
+    "Measurement posts rejected by the gateway allowlist.",
+);
+pub const INGEST_DROPPED_TAGS: MetricFamily = family(
+    "ruuvi_exporter_ingest_dropped_tags",
+    MetricType::Counter,
+    None,
+    "Tag observations ignored because of the tag allowlist.",
+);
+pub const INGEST_REQUESTS: MetricFamily = family(
+    "ruuvi_exporter_ingest_requests",
+    MetricType::Counter,
+    None,
+    "Measurement posts received, by outcome.",
+);
+pub const INGEST_PAYLOAD_SIZE: MetricFamily = family(
+    "ruuvi_exporter_ingest_payload_size_bytes",
+    MetricType::Histogram,
+    Some("bytes"),
+    "Size of the measurement posts received.",
+);
+pub const INGEST_DURATION: MetricFamily = family(
+    "ruuvi_exporter_ingest_duration_seconds",
+    MetricType::Histogram,
+    Some("seconds"),
+    "Time taken to process a measurement post.",
+);
+pub const TAGS_DECODED: MetricFamily = family(
+    "ruuvi_exporter_tags_decoded",
+    MetricType::Counter,
+    None,
+    "Tag advertisements decoded, by Ruuvi data format.",
+);
+pub const TAG_DECODE_FAILURES: MetricFamily = family(
+    "ruuvi_exporter_tag_decode_failures",
+    MetricType::Counter,
+    None,
+    "Tag advertisements that could not be decoded, by reason.",
+);
+pub const BUILD_INFO: MetricFamily = family(
+    "ruuvi_exporter_build_info",
+    MetricType::Gauge,
+    None,
+    "Version of the exporter. The value is always 1.",
+);
+
+/// All registered metric families in the order they are exported.
+pub const FAMILIES: &[&MetricFamily] = &[
+    &GATEWAY_UPDATE_TIMESTAMP,
+    &GATEWAY_NONCE,
+    &TAG_LAST_SEEN_TIMESTAMP,
+    &TAG_UP,
+    &TAG_SEQUENCE_NUMBER,
+    &TAG_PACKETS_RECEIVED,
+    &TAG_PACKETS_MISSED,
+    &TAG_TEMPERATURE,
+    &TAG_HUMIDITY,
+    &TAG_PRESSURE,
+    &TAG_MOVEMENT_COUNTER,
+    &TAG_ACCELERATION_X,
+    &TAG_ACCELERATION_Y,
+    &TAG_ACCELERATION_Z,
+    &TAG_BATTERY,
+    &TAG_TX_POWER,
+    &TAG_PM1_0,
+    &TAG_PM2_5,
+    &TAG_PM4_0,
+    &TAG_PM10_0,
+    &TAG_CO2,
+    &TAG_VOC_INDEX,
+    &TAG_NOX_INDEX,
+    &TAG_LUMINOSITY,
+    &TAG_DEW_POINT,
+    &TAG_FROST_POINT,
+    &TAG_ABSOLUTE_HUMIDITY,
+    &TAG_MIXING_RATIO,
+    &TAG_VAPOUR_PRESSURE_DEFICIT,
+    &TAG_TEMPERATURE_RAW,
+    &TAG_HUMIDITY_RAW,
+    &TAG_PRESSURE_RAW,
+    &TAG_CO2_RAW,
+    &TAG_RSSI,
+    &TAG_BEST_GATEWAY,
+    &CONFIG_LAST_RELOAD_SUCCESS,
+    &CONFIG_LAST_RELOAD_SUCCESS_TIMESTAMP,
+    &INGEST_UNAUTHORIZED_REQUESTS,
+    &METRICS_UNAUTHORIZED_REQUESTS,
+    &INGEST_DROPPED_MESSAGES,
+    &INGEST_DROPPED_TAGS,
+    &INGEST_REQUESTS,
+    &INGEST_PAYLOAD_SIZE,
+    &INGEST_DURATION,
+    &TAGS_DECODED,
+    &TAG_DECODE_FAILURES,
+    &BUILD_INFO,
+];
 
 #[derive(Clone)]
 pub struct LabelSet<'a> {
@@ -14,6 +450,7 @@ pub struct Metric<'a, V> {
     name: &'a str,
     labels: Vec<(&'a str, &'a str)>,
     value: V,
+    timestamp: Option<Epoch>,
 }
 
 pub fn metric(name: &str) -> MetricBuilder<'_> {
@@ -40,7 +477,6 @@ impl<'a> MetricBuilder<'a> {
         self
     }
 
-    #[allow(dead_code)]
     pub fn label(mut self, key: &'a str, value: &'a str) -> Self {
         self.labels.push((key, value));
         self
@@ -51,24 +487,286 @@ impl<'a> MetricBuilder<'a> {
             name: self.name,
             labels: self.labels,
             value,
+            timestamp: None,
         }
     }
 }
 
-impl<V: fmt::Display> fmt::Display for Metric<'_, V> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.name)?;
+impl<V> Metric<'_, V> {
+    /// Sets the time the sample was observed at, instead of leaving it to the scrape time.
+    pub fn timestamp(mut self, timestamp: Option<Epoch>) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    fn write_labels(&self, f: &mut impl Write) -> fmt::Result {
         if !self.labels.is_empty() {
             write!(f, "{{")?;
             for (i, (key, value)) in self.labels.iter().enumerate() {
                 if i > 0 {
                     write!(f, ",")?;
                 }
-                write!(f, "{key}=\"{value}\"")?;
+                debug_assert!(is_valid_label_name(key), "invalid label name {key:?}");
+                write!(f, "{key}=\"{}\"", EscapedLabelValue(value))?;
             }
             write!(f, "}}")?;
         }
-        write!(f, " {}", self.value)
+        Ok(())
+    }
+}
+
+/// Label value escaped per the exposition format: backslash, double quote and line feed.
+struct EscapedLabelValue<'a>(&'a str);
+
+impl fmt::Display for EscapedLabelValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '\\' => f.write_str("\\\\")?,
+                '"' => f.write_str("\\\"")?,
+                '\n' => f.write_str("\\n")?,
+                c => f.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// HELP text escaped per the exposition format. OpenMetrics additionally escapes double quotes.
+struct EscapedHelp<'a>(&'a str, Format);
+
+impl fmt::Display for EscapedHelp<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.1 {
+            Format::OpenMetrics => EscapedLabelValue(self.0).fmt(f),
+            Format::Prometheus => {
+                for c in self.0.chars() {
+                    match c {
+                        '\\' => f.write_str("\\\\")?,
+                        '\n' => f.write_str("\\n")?,
+                        c => f.write_char(c)?,
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Checks that `name` matches `[a-zA-Z_:][a-zA-Z0-9_:]*`.
+pub fn is_valid_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// Checks that `name` matches `[a-zA-Z_][a-zA-Z0-9_]*` and is not reserved for internal use.
+pub fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    !name.starts_with("__")
+        && chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Writes a timestamp in milliseconds as in the Prometheus text format.
+fn write_timestamp_millis(f: &mut impl Write, timestamp: Option<Epoch>) -> fmt::Result {
+    match timestamp {
+        Some(timestamp) => write!(f, " {:.0}", timestamp.to_unix_milliseconds()),
+        None => Ok(()),
+    }
+}
+
+/// Distribution of observed values over fixed buckets.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    /// Upper bounds of the buckets, in increasing order
+    bounds: &'static [f64],
+    /// Number of observations per bucket, not cumulative
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        debug_assert!(bounds.windows(2).all(|pair| pair[0] < pair[1]));
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        if let Some(bucket) = self.bounds.iter().position(|&bound| value <= bound) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// A sample stored by `MetricsWriter`, rendered on output.
+struct Sample {
+    /// Suffix of the sample name, used by histograms
+    suffix: &'static str,
+    labels: String,
+    value: String,
+    timestamp: Option<Epoch>,
+}
+
+/// Collects samples grouped by metric family and renders them in either exposition format.
+///
+/// Families are written in registry order, each with its metadata header. Families without
+/// samples are omitted.
+pub struct MetricsWriter {
+    families: Vec<(&'static MetricFamily, Vec<Sample>)>,
+}
+
+impl MetricsWriter {
+    pub fn new() -> Self {
+        Self {
+            families: FAMILIES
+                .iter()
+                .map(|&family| (family, Vec::new()))
+                .collect(),
+        }
+    }
+
+    pub fn push<V: fmt::Display>(&mut self, family: &'static MetricFamily, metric: &Metric<'_, V>) {
+        debug_assert_ne!(family.metric_type, MetricType::Histogram);
+        self.push_sample(family, "", metric);
+    }
+
+    /// Adds the bucket, sum and count samples of a histogram.
+    pub fn push_histogram(
+        &mut self,
+        family: &'static MetricFamily,
+        labels: &LabelSet,
+        histogram: &Histogram,
+    ) {
+        debug_assert_eq!(family.metric_type, MetricType::Histogram);
+        let mut cumulative = 0;
+        for (bound, count) in histogram.bounds.iter().zip(&histogram.counts) {
+            cumulative += count;
+            let le = format!("{bound:?}");
+            let bucket = metric(family.name).labels(labels).label("le", &le);
+            self.push_sample(family, "_bucket", &bucket.value(cumulative));
+        }
+        let bucket = metric(family.name).labels(labels).label("le", "+Inf");
+        self.push_sample(family, "_bucket", &bucket.value(histogram.count));
+        let sum = metric(family.name).labels(labels).value(histogram.sum);
+        self.push_sample(family, "_sum", &sum);
+        let count = metric(family.name).labels(labels).value(histogram.count);
+        self.push_sample(family, "_count", &count);
+    }
+
+    fn push_sample<V: fmt::Display>(
+        &mut self,
+        family: &'static MetricFamily,
+        suffix: &'static str,
+        metric: &Metric<'_, V>,
+    ) {
+        debug_assert_eq!(family.name, metric.name);
+        debug_assert!(is_valid_metric_name(family.name));
+        debug_assert!(family.unit.is_none_or(|unit| family.name.ends_with(unit)));
+
+        let mut labels = String::new();
+        metric
+            .write_labels(&mut labels)
+            .expect("Writing to a String cannot fail");
+        let sample = Sample {
+            suffix,
+            labels,
+            value: metric.value.to_string(),
+            timestamp: metric.timestamp,
+        };
+        match self
+            .families
+            .iter_mut()
+            .find(|(f, _)| f.name == family.name)
+        {
+            Some((_, samples)) => samples.push(sample),
+            None => self.families.push((family, vec![sample])),
+        }
+    }
+
+    pub fn render(&self, format: Format) -> String {
+        let mut out = String::new();
+        self.write(&mut out, format)
+            .expect("Writing to a String cannot fail");
+        out
+    }
+
+    fn write(&self, f: &mut impl Write, format: Format) -> fmt::Result {
+        for (family, samples) in self.families.iter().filter(|(_, s)| !s.is_empty()) {
+            // Counter samples carry the _total suffix, except for the legacy names in the
+            // Prometheus format. OpenMetrics names the family without it, the Prometheus format
+            // names the family like its samples.
+            let counter_suffix = match (family.metric_type, format) {
+                (MetricType::Counter, Format::Prometheus) if family.legacy_name => "",
+                (MetricType::Counter, _) => "_total",
+                _ => "",
+            };
+            let header_suffix = match format {
+                Format::Prometheus => counter_suffix,
+                Format::OpenMetrics => "",
+            };
+            writeln!(
+                f,
+                "# HELP {}{header_suffix} {}",
+                family.name,
+                EscapedHelp(family.help, format)
+            )?;
+            writeln!(
+                f,
+                "# TYPE {}{header_suffix} {}",
+                family.name, family.metric_type
+            )?;
+            if format == Format::OpenMetrics {
+                if let Some(unit) = family.unit {
+                    writeln!(f, "# UNIT {} {unit}", family.name)?;
+                }
+            }
+
+            for sample in samples {
+                let suffix = match family.metric_type {
+                    MetricType::Counter => counter_suffix,
+                    _ => sample.suffix,
+                };
+                write!(
+                    f,
+                    "{}{suffix}{} {}",
+                    family.name, sample.labels, sample.value
+                )?;
+                match (format, sample.timestamp) {
+                    (Format::Prometheus, timestamp) => write_timestamp_millis(f, timestamp)?,
+                    (Format::OpenMetrics, Some(timestamp)) => {
+                        write!(f, " {}", timestamp.to_unix_seconds())?;
+                    }
+                    (Format::OpenMetrics, None) => {}
+                }
+                writeln!(f)?;
+            }
+        }
+        if format == Format::OpenMetrics {
+            writeln!(f, "# EOF")?;
+        }
+        Ok(())
+    }
+}
+
+impl<V: fmt::Display> fmt::Display for Metric<'_, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        self.write_labels(f)?;
+        write!(f, " {}", self.value)?;
+        write_timestamp_millis(f, self.timestamp)
     }
 }
 
@@ -111,4 +809,196 @@ mod tests {
             "humidity{datacenter=\"eu-1\",rack=\"r42\"} 45"
         );
     }
+
+    #[test]
+    fn test_metrics_writer_groups_families() {
+        let mut writer = MetricsWriter::new();
+        for mac in ["a", "b"] {
+            let labels = labelset().label("mac", mac);
+            writer.push(
+                &TAG_MOVEMENT_COUNTER,
+                &metric(TAG_MOVEMENT_COUNTER.name).labels(&labels).value(3),
+            );
+            writer.push(
+                &TAG_TEMPERATURE,
+                &metric(TAG_TEMPERATURE.name).labels(&labels).value(20.5),
+            );
+        }
+
+        assert_eq!(
+            writer.render(Format::Prometheus),
+            r#"# HELP ruuvi_tag_temperature_celsius Temperature measured by the tag.
+# TYPE ruuvi_tag_temperature_celsius gauge
+ruuvi_tag_temperature_celsius{mac="a"} 20.5
+ruuvi_tag_temperature_celsius{mac="b"} 20.5
+# HELP ruuvi_tag_movement_counter Number of movements detected by the tag's accelerometer.
+# TYPE ruuvi_tag_movement_counter counter
+ruuvi_tag_movement_counter{mac="a"} 3
+ruuvi_tag_movement_counter{mac="b"} 3
+"#
+        );
+    }
+
+    #[test]
+    fn test_metrics_writer_openmetrics() {
+        let mut writer = MetricsWriter::new();
+        let labels = labelset().label("mac", "a");
+        let timestamp = Some(Epoch::from_unix_seconds(1609459210.5));
+        writer.push(
+            &TAG_MOVEMENT_COUNTER,
+            &metric(TAG_MOVEMENT_COUNTER.name)
+                .labels(&labels)
+                .value(3)
+                .timestamp(timestamp),
+        );
+        writer.push(
+            &TAG_TEMPERATURE,
+            &metric(TAG_TEMPERATURE.name)
+                .labels(&labels)
+                .value(20.5)
+                .timestamp(timestamp),
+        );
+        writer.push(&TAG_UP, &metric(TAG_UP.name).labels(&labels).value(1));
+
+        assert_eq!(
+            writer.render(Format::OpenMetrics),
+            r#"# HELP ruuvi_tag_up Whether the tag has been heard within its timeout.
+# TYPE ruuvi_tag_up gauge
+ruuvi_tag_up{mac="a"} 1
+# HELP ruuvi_tag_temperature_celsius Temperature measured by the tag.
+# TYPE ruuvi_tag_temperature_celsius gauge
+# UNIT ruuvi_tag_temperature_celsius celsius
+ruuvi_tag_temperature_celsius{mac="a"} 20.5 1609459210.5
+# HELP ruuvi_tag_movement_counter Number of movements detected by the tag's accelerometer.
+# TYPE ruuvi_tag_movement_counter counter
+ruuvi_tag_movement_counter_total{mac="a"} 3 1609459210.5
+# EOF
+"#
+        );
+
+        assert_eq!(
+            writer.render(Format::Prometheus),
+            r#"# HELP ruuvi_tag_up Whether the tag has been heard within its timeout.
+# TYPE ruuvi_tag_up gauge
+ruuvi_tag_up{mac="a"} 1
+# HELP ruuvi_tag_temperature_celsius Temperature measured by the tag.
+# TYPE ruuvi_tag_temperature_celsius gauge
+ruuvi_tag_temperature_celsius{mac="a"} 20.5 1609459210500
+# HELP ruuvi_tag_movement_counter Number of movements detected by the tag's accelerometer.
+# TYPE ruuvi_tag_movement_counter counter
+ruuvi_tag_movement_counter{mac="a"} 3 1609459210500
+"#
+        );
+    }
+
+    #[test]
+    fn test_format_negotiation() {
+        assert_eq!(Format::negotiate(None), Format::Prometheus);
+        assert_eq!(Format::negotiate(Some("*/*")), Format::Prometheus);
+        assert_eq!(
+            Format::negotiate(Some("application/openmetrics-text")),
+            Format::OpenMetrics
+        );
+        // Header sent by Prometheus 2.x
+        assert_eq!(
+            Format::negotiate(Some(
+                "application/openmetrics-text;version=1.0.0,application/openmetrics-text;version=0.0.1;q=0.75,text/plain;version=0.0.4;q=0.5,*/*;q=0.1"
+            )),
+            Format::OpenMetrics
+        );
+        assert_eq!(
+            Format::negotiate(Some(
+                "application/openmetrics-text; q=0.3, text/plain; version=0.0.4; q=0.5"
+            )),
+            Format::Prometheus
+        );
+        assert_eq!(
+            Format::negotiate(Some("application/openmetrics-text;q=0")),
+            Format::Prometheus
+        );
+    }
+
+    #[test]
+    fn test_label_value_escaping() {
+        let m = metric("temperature")
+            .label("name", "Kid's \"room\"\\2\nfloor")
+            .value(20);
+        assert_eq!(
+            m.to_string(),
+            r#"temperature{name="Kid's \"room\"\\2\nfloor"} 20"#
+        );
+    }
+
+    #[test]
+    fn test_help_escaping() {
+        let help = "Line \"one\"\\\nline two";
+        assert_eq!(
+            EscapedHelp(help, Format::Prometheus).to_string(),
+            r#"Line "one"\\\nline two"#
+        );
+        assert_eq!(
+            EscapedHelp(help, Format::OpenMetrics).to_string(),
+            r#"Line \"one\"\\\nline two"#
+        );
+    }
+
+    #[test]
+    fn test_name_validation() {
+        assert!(is_valid_metric_name("ruuvi_tag_rssi_dBm"));
+        assert!(is_valid_metric_name("job:rule:rate5m"));
+        assert!(!is_valid_metric_name(""));
+        assert!(!is_valid_metric_name("1metric"));
+        assert!(!is_valid_metric_name("metric-name"));
+
+        assert!(is_valid_label_name("gw_mac"));
+        assert!(is_valid_label_name("_private"));
+        assert!(!is_valid_label_name(""));
+        assert!(!is_valid_label_name("__name__"));
+        assert!(!is_valid_label_name("room:floor"));
+        assert!(!is_valid_label_name("2nd_floor"));
+        assert!(!is_valid_label_name("huone_ä"));
+    }
+
+    #[test]
+    fn test_registry_units_match_names() {
+        for family in FAMILIES {
+            assert!(is_valid_metric_name(family.name), "{}", family.name);
+            if let Some(unit) = family.unit {
+                assert!(
+                    family.name.ends_with(&format!("_{unit}")),
+                    "{}",
+                    family.name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_histogram() {
+        const BOUNDS: &[f64] = &[0.5, 1.0, 2.5];
+        let mut histogram = Histogram::new(BOUNDS);
+        for value in [0.1, 0.5, 2.0, 7.0] {
+            histogram.observe(value);
+        }
+
+        let mut metrics = MetricsWriter::new();
+        metrics.push_histogram(&INGEST_DURATION, &labelset().label("gw", "a"), &histogram);
+        let expected_samples = r#"ruuvi_exporter_ingest_duration_seconds_bucket{gw="a",le="0.5"} 2
+ruuvi_exporter_ingest_duration_seconds_bucket{gw="a",le="1.0"} 2
+ruuvi_exporter_ingest_duration_seconds_bucket{gw="a",le="2.5"} 3
+ruuvi_exporter_ingest_duration_seconds_bucket{gw="a",le="+Inf"} 4
+ruuvi_exporter_ingest_duration_seconds_sum{gw="a"} 9.6
+ruuvi_exporter_ingest_duration_seconds_count{gw="a"} 4
+"#;
+        assert!(
+            metrics
+                .render(Format::Prometheus)
+                .ends_with(&format!("histogram\n{expected_samples}")),
+            "{}",
+            metrics.render(Format::Prometheus)
+        );
+        assert!(metrics.render(Format::OpenMetrics).ends_with(&format!(
+            "# UNIT ruuvi_exporter_ingest_duration_seconds seconds\n{expected_samples}# EOF\n"
+        )));
+    }
 }
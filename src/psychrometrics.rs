@@ -0,0 +1,186 @@
+use serde::{Deserialize, Deserializer};
+use std::str::FromStr;
+
+/// Magnus formula coefficients of Alduchov and Eskridge (1996) over liquid water: hPa,
+/// unitless, °C
+const WATER: (f64, f64, f64) = (6.1094, 17.625, 243.04);
+/// Magnus coefficients over ice: hPa, unitless, °C
+const ICE: (f64, f64, f64) = (6.1121, 22.587, 273.86);
+/// Specific gas constant of water vapour in J/(kg·K)
+const WATER_VAPOUR_GAS_CONSTANT: f64 = 461.5;
+/// Ratio of the molar masses of water and dry air
+const MOLAR_MASS_RATIO: f64 = 0.622;
+
+/// Saturation vapour pressure over water at `temperature` °C, in Pa.
+pub fn saturation_vapour_pressure(temperature: f64) -> f64 {
+    let (c, a, b) = WATER;
+    c * 100.0 * (a * temperature / (b + temperature)).exp()
+}
+
+/// Partial pressure of water vapour in Pa at `temperature` °C and `humidity` % relative
+/// humidity.
+pub fn vapour_pressure(temperature: f64, humidity: f64) -> f64 {
+    humidity / 100.0 * saturation_vapour_pressure(temperature)
+}
+
+/// Temperature in °C at which the vapour pressure `vapour_pressure` Pa saturates, over the
+/// surface described by `coefficients`.
+fn saturation_temperature(vapour_pressure: f64, (c, a, b): (f64, f64, f64)) -> f64 {
+    let gamma = (vapour_pressure / (c * 100.0)).ln();
+    b * gamma / (a - gamma)
+}
+
+/// Dew point in °C. Undefined for completely dry air.
+pub fn dew_point(temperature: f64, humidity: f64) -> Option<f64> {
+    (humidity > 0.0).then(|| saturation_temperature(vapour_pressure(temperature, humidity), WATER))
+}
+
+/// Frost point in °C, the temperature at which frost forms. Undefined for completely dry air.
+pub fn frost_point(temperature: f64, humidity: f64) -> Option<f64> {
+    (humidity > 0.0).then(|| saturation_temperature(vapour_pressure(temperature, humidity), ICE))
+}
+
+/// Mass of water vapour per volume of air, in g/m³.
+pub fn absolute_humidity(temperature: f64, humidity: f64) -> f64 {
+    let kelvin = temperature + 273.15;
+    vapour_pressure(temperature, humidity) / (WATER_VAPOUR_GAS_CONSTANT * kelvin) * 1000.0
+}
+
+/// Mass of water vapour per mass of dry air, in kg/kg, at `pressure` Pa.
+pub fn mixing_ratio(temperature: f64, humidity: f64, pressure: f64) -> f64 {
+    let vapour_pressure = vapour_pressure(temperature, humidity);
+    MOLAR_MASS_RATIO * vapour_pressure / (pressure - vapour_pressure)
+}
+
+/// Difference between the saturation and the actual vapour pressure, in Pa.
+pub fn vapour_pressure_deficit(temperature: f64, humidity: f64) -> f64 {
+    saturation_vapour_pressure(temperature) - vapour_pressure(temperature, humidity)
+}
+
+/// A derived metric that can be enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DerivedMetric {
+    DewPoint,
+    FrostPoint,
+    AbsoluteHumidity,
+    MixingRatio,
+    VapourPressureDeficit,
+}
+
+impl DerivedMetric {
+    const ALL: [DerivedMetric; 5] = [
+        DerivedMetric::DewPoint,
+        DerivedMetric::FrostPoint,
+        DerivedMetric::AbsoluteHumidity,
+        DerivedMetric::MixingRatio,
+        DerivedMetric::VapourPressureDeficit,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            DerivedMetric::DewPoint => "dew_point",
+            DerivedMetric::FrostPoint => "frost_point",
+            DerivedMetric::AbsoluteHumidity => "absolute_humidity",
+            DerivedMetric::MixingRatio => "mixing_ratio",
+            DerivedMetric::VapourPressureDeficit => "vapour_pressure_deficit",
+        }
+    }
+}
+
+/// Set of enabled derived metrics, given as a comma separated list of names or `all`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DerivedMetrics {
+    enabled: [bool; DerivedMetric::ALL.len()],
+}
+
+impl DerivedMetrics {
+    pub fn is_enabled(&self, metric: DerivedMetric) -> bool {
+        self.enabled[metric as usize]
+    }
+
+    fn from_names<'a>(names: impl IntoIterator<Item = &'a str>) -> Result<Self, String> {
+        let mut metrics = Self::default();
+        for name in names {
+            let name = name.trim();
+            if name == "all" {
+                metrics.enabled = [true; DerivedMetric::ALL.len()];
+                continue;
+            }
+            let Some(metric) = DerivedMetric::ALL.into_iter().find(|m| m.name() == name) else {
+                let known: Vec<_> = DerivedMetric::ALL.iter().map(|m| m.name()).collect();
+                return Err(format!(
+                    "unknown derived metric {name:?}, expected all or one of {}",
+                    known.join(", ")
+                ));
+            };
+            metrics.enabled[metric as usize] = true;
+        }
+        Ok(metrics)
+    }
+}
+
+impl FromStr for DerivedMetrics {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_names(s.split(',').filter(|name| !name.trim().is_empty()))
+    }
+}
+
+impl<'de> Deserialize<'de> for DerivedMetrics {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let names = Vec::<String>::deserialize(deserializer)?;
+        Self::from_names(names.iter().map(String::as_str)).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{actual} is not within {tolerance} of {expected}"
+        );
+    }
+
+    #[test]
+    fn test_formulas() {
+        assert_close(saturation_vapour_pressure(0.0), 610.94, 0.01);
+        assert_close(saturation_vapour_pressure(20.0), 2333.4, 1.0);
+        assert_close(dew_point(20.0, 50.0).unwrap(), 9.26, 0.05);
+        assert_close(dew_point(25.0, 100.0).unwrap(), 25.0, 1e-9);
+        assert_close(absolute_humidity(20.0, 50.0), 8.62, 0.01);
+        assert_close(mixing_ratio(20.0, 50.0, 101_325.0), 0.007_245, 0.000_01);
+        assert_close(vapour_pressure_deficit(20.0, 50.0), 1166.7, 1.0);
+        assert_close(vapour_pressure_deficit(20.0, 100.0), 0.0, 1e-9);
+
+        // Below freezing frost forms before dew would
+        let dew = dew_point(-5.0, 90.0).unwrap();
+        let frost = frost_point(-5.0, 90.0).unwrap();
+        assert!(dew < frost && frost < -5.0, "dew {dew}, frost {frost}");
+
+        assert_eq!(dew_point(20.0, 0.0), None);
+        assert_eq!(frost_point(20.0, 0.0), None);
+    }
+
+    #[test]
+    fn test_parse_derived_metrics() {
+        let metrics: DerivedMetrics = "dew_point, mixing_ratio".parse().unwrap();
+        assert!(metrics.is_enabled(DerivedMetric::DewPoint));
+        assert!(metrics.is_enabled(DerivedMetric::MixingRatio));
+        assert!(!metrics.is_enabled(DerivedMetric::FrostPoint));
+
+        let all: DerivedMetrics = "all".parse().unwrap();
+        assert!(DerivedMetric::ALL.iter().all(|&m| all.is_enabled(m)));
+        assert_eq!("".parse(), Ok(DerivedMetrics::default()));
+        assert!("dewpoint".parse::<DerivedMetrics>().is_err());
+
+        let metrics: DerivedMetrics =
+            serde_yaml::from_str("[frost_point, vapour_pressure_deficit]").unwrap();
+        assert!(metrics.is_enabled(DerivedMetric::FrostPoint));
+        assert!(metrics.is_enabled(DerivedMetric::VapourPressureDeficit));
+        assert!(serde_yaml::from_str::<DerivedMetrics>("[humidex]").is_err());
+    }
+}
@@ -0,0 +1,246 @@
+use hifitime::Epoch;
+use parking_lot::{Mutex, RwLock};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tracing::{info, warn};
+
+use crate::config::MacMapping;
+use crate::metrics::{self, metric, MetricsWriter};
+
+/// How often watched files are checked for modifications.
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Outcome of the latest attempt to load the mapping file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReloadStatus {
+    pub success: bool,
+    /// Time of the latest successful load
+    pub last_success: Epoch,
+}
+
+/// MAC mapping that can be replaced at runtime without losing the measurement state.
+pub struct MappingStore {
+    path: Option<PathBuf>,
+    current: RwLock<Arc<MacMapping>>,
+    status: Mutex<ReloadStatus>,
+}
+
+impl MappingStore {
+    pub fn new(path: Option<PathBuf>, mapping: MacMapping, now: Epoch) -> Self {
+        Self {
+            path,
+            current: RwLock::new(Arc::new(mapping)),
+            status: Mutex::new(ReloadStatus {
+                success: true,
+                last_success: now,
+            }),
+        }
+    }
+
+    /// Returns the current mapping. The mapping stays consistent even if it is reloaded while
+    /// in use.
+    pub fn get(&self) -> Arc<MacMapping> {
+        self.current.read().clone()
+    }
+
+    pub fn status(&self) -> ReloadStatus {
+        *self.status.lock()
+    }
+
+    /// Loads the mapping file again and swaps it in. On failure the old mapping is kept.
+    pub fn reload(&self, now: Epoch) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let result = MacMapping::load(path);
+        let mut status = self.status.lock();
+        status.success = result.is_ok();
+        let mapping = result?;
+        status.last_success = now;
+        *self.current.write() = Arc::new(mapping);
+        Ok(())
+    }
+
+    fn reload_and_log(&self) {
+        let now = Epoch::now().expect("Failed to read system time");
+        match self.reload(now) {
+            Ok(()) => info!("Reloaded MAC mapping file"),
+            Err(err) => warn!("Failed to reload MAC mapping file, keeping the old mapping: {err}"),
+        }
+    }
+
+    pub fn collect_metrics(&self, metrics: &mut MetricsWriter) {
+        let status = self.status();
+        metrics.push(
+            &metrics::CONFIG_LAST_RELOAD_SUCCESS,
+            &metric(metrics::CONFIG_LAST_RELOAD_SUCCESS.name).value(u8::from(status.success)),
+        );
+        metrics.push(
+            &metrics::CONFIG_LAST_RELOAD_SUCCESS_TIMESTAMP,
+            &metric(metrics::CONFIG_LAST_RELOAD_SUCCESS_TIMESTAMP.name)
+                .value(status.last_success.to_unix_seconds()),
+        );
+    }
+}
+
+/// Reloads the mapping whenever the process receives SIGHUP or the mapping file changes.
+pub fn spawn_watchers(store: &Arc<MappingStore>) {
+    let Some(path) = store.path.clone() else {
+        return;
+    };
+    let store = store.clone();
+    watch_files(vec![path], move || store.reload_and_log());
+}
+
+/// Calls `reload` whenever the process receives SIGHUP or one of `paths` changes.
+pub fn watch_files(paths: Vec<PathBuf>, reload: impl Fn() + Send + Sync + 'static) {
+    let reload = Arc::new(reload);
+
+    #[cfg(unix)]
+    tokio::spawn({
+        let reload = reload.clone();
+        async move {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let mut hangup = signal(SignalKind::hangup()).expect("Failed to listen for SIGHUP");
+            while hangup.recv().await.is_some() {
+                reload();
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        let mut versions: Vec<_> = paths.iter().map(|path| file_version(path)).collect();
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let current: Vec<_> = paths.iter().map(|path| file_version(path)).collect();
+            if current != versions {
+                versions = current;
+                reload();
+            }
+        }
+    });
+}
+
+/// Identifies the contents of a watched file. The length and inode catch rewrites within the
+/// resolution of the modification time and files replaced by renaming another file over them.
+#[derive(Debug, PartialEq)]
+struct FileVersion {
+    modified: SystemTime,
+    len: u64,
+    #[cfg(unix)]
+    inode: u64,
+}
+
+fn file_version(path: &Path) -> Option<FileVersion> {
+    let metadata = fs::metadata(path).ok()?;
+    Some(FileVersion {
+        modified: metadata.modified().ok()?,
+        len: metadata.len(),
+        #[cfg(unix)]
+        inode: std::os::unix::fs::MetadataExt::ino(&metadata),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Seek, Write};
+    use tempfile::NamedTempFile;
+
+    fn write_mapping(file: &mut NamedTempFile, content: &str) {
+        let file = file.as_file_mut();
+        file.set_len(0).unwrap();
+        file.rewind().unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        file.sync_all().unwrap();
+    }
+
+    #[test]
+    fn test_reload_swaps_mapping() {
+        let mut file = NamedTempFile::new().unwrap();
+        write_mapping(&mut file, r#""AA:BB:CC:DD:EE:FF": "Kitchen""#);
+        let mapping = MacMapping::load(file.path()).unwrap();
+        let store = MappingStore::new(
+            Some(file.path().to_path_buf()),
+            mapping,
+            Epoch::from_unix_seconds(100.0),
+        );
+        let old = store.get();
+
+        write_mapping(&mut file, r#""AA:BB:CC:DD:EE:FF": "Living Room""#);
+        store.reload(Epoch::from_unix_seconds(200.0)).unwrap();
+
+        assert_eq!(
+            store.get().lookup(&"AA:BB:CC:DD:EE:FF".parse().unwrap()),
+            Some("Living Room")
+        );
+        // Readers holding the old mapping are not affected
+        assert_eq!(
+            old.lookup(&"AA:BB:CC:DD:EE:FF".parse().unwrap()),
+            Some("Kitchen")
+        );
+        assert_eq!(
+            store.status(),
+            ReloadStatus {
+                success: true,
+                last_success: Epoch::from_unix_seconds(200.0),
+            }
+        );
+    }
+
+    #[test]
+    fn test_file_version() {
+        let mut file = NamedTempFile::new().unwrap();
+        write_mapping(&mut file, r#""AA:BB:CC:DD:EE:FF": "Kitchen""#);
+        let version = file_version(file.path()).unwrap();
+        assert_eq!(file_version(file.path()).as_ref(), Some(&version));
+
+        // Noticed even if the modification time has not ticked
+        write_mapping(&mut file, r#""AA:BB:CC:DD:EE:FF": "Living Room""#);
+        assert_ne!(file_version(file.path()), Some(version));
+
+        assert_eq!(file_version(Path::new("/nonexistent/mapping.yaml")), None);
+    }
+
+    #[test]
+    fn test_failed_reload_keeps_old_mapping() {
+        let mut file = NamedTempFile::new().unwrap();
+        write_mapping(&mut file, r#""AA:BB:CC:DD:EE:FF": "Kitchen""#);
+        let mapping = MacMapping::load(file.path()).unwrap();
+        let store = MappingStore::new(
+            Some(file.path().to_path_buf()),
+            mapping,
+            Epoch::from_unix_seconds(100.0),
+        );
+
+        write_mapping(&mut file, "invalid: yaml: content:");
+        assert!(store.reload(Epoch::from_unix_seconds(200.0)).is_err());
+
+        assert_eq!(
+            store.get().lookup(&"AA:BB:CC:DD:EE:FF".parse().unwrap()),
+            Some("Kitchen")
+        );
+        assert_eq!(
+            store.status(),
+            ReloadStatus {
+                success: false,
+                last_success: Epoch::from_unix_seconds(100.0),
+            }
+        );
+
+        let mut metrics = MetricsWriter::new();
+        store.collect_metrics(&mut metrics);
+        let output = metrics.render(metrics::Format::Prometheus);
+        assert!(output.contains("ruuvi_exporter_config_last_reload_success 0\n"));
+        assert!(
+            output.contains("ruuvi_exporter_config_last_reload_success_timestamp_seconds 100\n")
+        );
+    }
+}
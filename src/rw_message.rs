@@ -1,13 +1,14 @@
 use std::{collections::HashMap, fmt};
 
-use hex::FromHexError;
 use hifitime::{Duration, Epoch};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::mac::{MacAddress, MacAddressParseError};
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct TagMessage {
-    pub name: String,
+    pub mac: MacAddress,
     pub data: Vec<u8>,
     pub timestamp: Epoch,
     pub rssi: i32,
@@ -19,8 +20,18 @@ pub struct GwMessage {
     pub coordinates: String,
     pub timestamp: Epoch,
     pub nonce: u64,
-    pub gw_mac: String,
+    pub gw_mac: MacAddress,
     pub tags: Vec<TagMessage>,
+    /// Tags whose advertisement data was not valid hex, and are left out of `tags`
+    pub invalid_tags: Vec<MacAddress>,
+    /// Tag keys that are not valid MAC addresses, their tags are left out of `tags`
+    pub invalid_macs: Vec<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum GwMessageError {
+    #[error(transparent)]
+    Mac(#[from] MacAddressParseError),
 }
 
 // Raw messages as they are sent over HTTP
@@ -32,19 +43,6 @@ struct RawTagMessage {
     pub rssi: i32,
 }
 
-impl TryFrom<(String, RawTagMessage)> for TagMessage {
-    type Error = FromHexError;
-
-    fn try_from((name, msg): (String, RawTagMessage)) -> Result<Self, Self::Error> {
-        Ok(TagMessage {
-            name,
-            data: hex::decode(msg.data)?,
-            timestamp: unix_timestamp_to_epoch(msg.timestamp),
-            rssi: msg.rssi,
-        })
-    }
-}
-
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct RawGwMessage {
     pub coordinates: String,
@@ -60,19 +58,38 @@ struct RawGwWrapper {
 }
 
 impl TryFrom<RawGwWrapper> for GwMessage {
-    type Error = FromHexError;
+    type Error = GwMessageError;
     fn try_from(wrapper: RawGwWrapper) -> Result<Self, Self::Error> {
         let data = wrapper.data;
 
-        let tags: Result<Vec<TagMessage>, FromHexError> =
-            data.tags.into_iter().map(TryFrom::try_from).collect();
+        let mut tags = Vec::with_capacity(data.tags.len());
+        let mut invalid_tags = Vec::new();
+        let mut invalid_macs = Vec::new();
+        for (key, tag) in data.tags {
+            // A single garbled tag should not cause the whole message to be dropped
+            let Ok(mac) = key.parse::<MacAddress>() else {
+                invalid_macs.push(key);
+                continue;
+            };
+            match hex::decode(&tag.data) {
+                Ok(data) => tags.push(TagMessage {
+                    mac,
+                    data,
+                    timestamp: unix_timestamp_to_epoch(tag.timestamp),
+                    rssi: tag.rssi,
+                }),
+                Err(_) => invalid_tags.push(mac),
+            }
+        }
 
         Ok(GwMessage {
             coordinates: data.coordinates,
             timestamp: unix_timestamp_to_epoch(data.timestamp),
             nonce: data.nonce,
-            gw_mac: data.gw_mac,
-            tags: tags?,
+            gw_mac: data.gw_mac.parse()?,
+            tags,
+            invalid_tags,
+            invalid_macs,
         })
     }
 }
@@ -137,7 +154,32 @@ mod tests {
     fn gw_message_parsing() {
         // Example message captured from Ruuvi Gateway
         let raw = r#"{"data":{"coordinates":"","gw_mac":"FF:81:4E:A5:22:E7","nonce":3267643756,"tags":{"DD:19:92:CB:60:21":{"data":"0201061BFF9904050FE0337CC4ABFC1400340024A5B6EBA544DD1992CB6021","rssi":-50,"timestamp":1736885086},"DE:4F:BC:29:EC:B5":{"data":"0201061BFF9904050FF33391C47D0008FFF403F8837637EE6EDE4FBC29ECB5","rssi":-63,"timestamp":1736885085}},"timestamp":1736885086}}"#;
-        let _: GwMessage = serde_json::from_str(raw).unwrap();
+        let msg: GwMessage = serde_json::from_str(raw).unwrap();
+        assert_eq!(msg.gw_mac.to_string(), "FF:81:4E:A5:22:E7");
+        assert_eq!(msg.tags.len(), 2);
+    }
+
+    #[test]
+    fn gw_message_invalid_mac() {
+        let raw = r#"{"data":{"coordinates":"","gw_mac":"not a mac","nonce":1,"tags":{},"timestamp":1736885086}}"#;
+        assert!(serde_json::from_str::<GwMessage>(raw).is_err());
+
+        // Only the tag with the invalid MAC is dropped
+        let raw = r#"{"data":{"coordinates":"","gw_mac":"FF:81:4E:A5:22:E7","nonce":1,"tags":{"DD:19:92:CB:60":{"data":"020106","rssi":-50,"timestamp":1736885086},"DE:4F:BC:29:EC:B5":{"data":"020106","rssi":-63,"timestamp":1736885085}},"timestamp":1736885086}}"#;
+        let msg: GwMessage = serde_json::from_str(raw).unwrap();
+        assert_eq!(msg.tags.len(), 1);
+        assert_eq!(msg.tags[0].mac.to_string(), "DE:4F:BC:29:EC:B5");
+        assert_eq!(msg.invalid_macs, ["DD:19:92:CB:60"]);
+    }
+
+    #[test]
+    fn gw_message_invalid_hex() {
+        let raw = r#"{"data":{"coordinates":"","gw_mac":"FF:81:4E:A5:22:E7","nonce":1,"tags":{"DD:19:92:CB:60:21":{"data":"0201061","rssi":-50,"timestamp":1736885086},"DE:4F:BC:29:EC:B5":{"data":"020106","rssi":-63,"timestamp":1736885085}},"timestamp":1736885086}}"#;
+        let msg: GwMessage = serde_json::from_str(raw).unwrap();
+        assert_eq!(msg.tags.len(), 1);
+        assert_eq!(msg.tags[0].mac.to_string(), "DE:4F:BC:29:EC:B5");
+        assert_eq!(msg.invalid_tags.len(), 1);
+        assert_eq!(msg.invalid_tags[0].to_string(), "DD:19:92:CB:60:21");
     }
 
     #[test]
@@ -0,0 +1,239 @@
+use parking_lot::Mutex;
+use ruuvi_decoders::RuuviData;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::metrics::{self, labelset, metric, Histogram, MetricsWriter};
+
+/// Buckets of the ingest payload size histogram, in bytes.
+const PAYLOAD_SIZE_BUCKETS: &[f64] = &[256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262_144.0];
+/// Buckets of the ingest processing time histogram, in seconds.
+const DURATION_BUCKETS: &[f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1];
+
+/// Outcome of a measurement post.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IngestResult {
+    Accepted,
+    /// The body was not a valid gateway message
+    Invalid,
+    Unauthorized,
+    Forbidden,
+}
+
+impl IngestResult {
+    const ALL: [IngestResult; 4] = [
+        IngestResult::Accepted,
+        IngestResult::Invalid,
+        IngestResult::Unauthorized,
+        IngestResult::Forbidden,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            IngestResult::Accepted => "accepted",
+            IngestResult::Invalid => "invalid",
+            IngestResult::Unauthorized => "unauthorized",
+            IngestResult::Forbidden => "forbidden",
+        }
+    }
+}
+
+/// Statistics of the measurement posts received.
+pub struct IngestStats {
+    requests: [AtomicU64; IngestResult::ALL.len()],
+    payload_size: Mutex<Histogram>,
+    duration: Mutex<Histogram>,
+}
+
+impl IngestStats {
+    pub fn new() -> Self {
+        Self {
+            requests: Default::default(),
+            payload_size: Mutex::new(Histogram::new(PAYLOAD_SIZE_BUCKETS)),
+            duration: Mutex::new(Histogram::new(DURATION_BUCKETS)),
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    pub fn record(&self, result: IngestResult, payload_size: usize, duration: Duration) {
+        self.requests[result as usize].fetch_add(1, Ordering::Relaxed);
+        self.payload_size.lock().observe(payload_size as f64);
+        self.duration.lock().observe(duration.as_secs_f64());
+    }
+
+    pub fn collect_metrics(&self, metrics: &mut MetricsWriter) {
+        for result in IngestResult::ALL {
+            metrics.push(
+                &metrics::INGEST_REQUESTS,
+                &metric(metrics::INGEST_REQUESTS.name)
+                    .label("result", result.label())
+                    .value(self.requests[result as usize].load(Ordering::Relaxed)),
+            );
+        }
+        metrics.push_histogram(
+            &metrics::INGEST_PAYLOAD_SIZE,
+            &labelset(),
+            &self.payload_size.lock(),
+        );
+        metrics.push_histogram(
+            &metrics::INGEST_DURATION,
+            &labelset(),
+            &self.duration.lock(),
+        );
+    }
+}
+
+/// Reason why a tag advertisement could not be decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DecodeFailure {
+    /// The advertisement data was not valid hex
+    BadHex,
+    /// The tag was reported under a key that is not a MAC address
+    BadMac,
+    /// The advertisement ended in the middle of an AD structure
+    Truncated,
+    /// The advertisement had no Ruuvi manufacturer specific data
+    NoRuuviData,
+    /// The Ruuvi data could not be decoded
+    DecodeError,
+}
+
+impl DecodeFailure {
+    const ALL: [DecodeFailure; 5] = [
+        DecodeFailure::BadHex,
+        DecodeFailure::BadMac,
+        DecodeFailure::Truncated,
+        DecodeFailure::NoRuuviData,
+        DecodeFailure::DecodeError,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            DecodeFailure::BadHex => "bad_hex",
+            DecodeFailure::BadMac => "bad_mac",
+            DecodeFailure::Truncated => "truncated_advertisement",
+            DecodeFailure::NoRuuviData => "no_ruuvi_data",
+            DecodeFailure::DecodeError => "decode_error",
+        }
+    }
+}
+
+/// Ruuvi data formats, in the order they are exported.
+const DATA_FORMATS: [&str; 3] = ["5", "6", "E1"];
+
+fn data_format(values: &RuuviData) -> usize {
+    match values {
+        RuuviData::V5(_) => 0,
+        RuuviData::V6(_) => 1,
+        RuuviData::E1(_) => 2,
+    }
+}
+
+/// Name of the data format of `values`, e.g. "5".
+pub fn data_format_name(values: &RuuviData) -> &'static str {
+    DATA_FORMATS[data_format(values)]
+}
+
+/// Counts of decoded tag advertisements and decode failures.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DecodeStats {
+    decoded: [u64; DATA_FORMATS.len()],
+    failures: [u64; DecodeFailure::ALL.len()],
+}
+
+impl DecodeStats {
+    pub fn record_decoded(&mut self, values: &RuuviData) {
+        self.decoded[data_format(values)] += 1;
+    }
+
+    pub fn record_failure(&mut self, failure: DecodeFailure) {
+        self.failures[failure as usize] += 1;
+    }
+
+    pub fn failures(&self, failure: DecodeFailure) -> u64 {
+        self.failures[failure as usize]
+    }
+
+    pub fn collect_metrics(&self, metrics: &mut MetricsWriter) {
+        for (format, count) in DATA_FORMATS.iter().zip(self.decoded) {
+            metrics.push(
+                &metrics::TAGS_DECODED,
+                &metric(metrics::TAGS_DECODED.name)
+                    .label("format", format)
+                    .value(count),
+            );
+        }
+        for failure in DecodeFailure::ALL {
+            metrics.push(
+                &metrics::TAG_DECODE_FAILURES,
+                &metric(metrics::TAG_DECODE_FAILURES.name)
+                    .label("reason", failure.label())
+                    .value(self.failures(failure)),
+            );
+        }
+    }
+}
+
+/// Exports the version of the exporter.
+pub fn collect_build_info(metrics: &mut MetricsWriter) {
+    metrics.push(
+        &metrics::BUILD_INFO,
+        &metric(metrics::BUILD_INFO.name)
+            .label("version", env!("CARGO_PKG_VERSION"))
+            .value(1),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ingest_stats() {
+        let stats = IngestStats::new();
+        stats.record(IngestResult::Accepted, 300, Duration::from_micros(200));
+        stats.record(IngestResult::Accepted, 100, Duration::from_micros(50));
+        stats.record(IngestResult::Invalid, 10, Duration::from_micros(10));
+
+        let mut metrics = MetricsWriter::new();
+        stats.collect_metrics(&mut metrics);
+        let output = metrics.render(metrics::Format::Prometheus);
+        for line in [
+            r#"ruuvi_exporter_ingest_requests_total{result="accepted"} 2"#,
+            r#"ruuvi_exporter_ingest_requests_total{result="invalid"} 1"#,
+            r#"ruuvi_exporter_ingest_requests_total{result="forbidden"} 0"#,
+            r#"ruuvi_exporter_ingest_payload_size_bytes_bucket{le="256.0"} 2"#,
+            r#"ruuvi_exporter_ingest_payload_size_bytes_bucket{le="1024.0"} 3"#,
+            "ruuvi_exporter_ingest_payload_size_bytes_sum 410",
+            r#"ruuvi_exporter_ingest_duration_seconds_bucket{le="0.0001"} 2"#,
+            "ruuvi_exporter_ingest_duration_seconds_count 3",
+        ] {
+            assert!(output.contains(&format!("{line}\n")), "{line}\n{output}");
+        }
+    }
+
+    #[test]
+    fn test_decode_stats() {
+        let mut stats = DecodeStats::default();
+        let data = hex::decode("050FE0337CC4ABFC1400340024A5B6EBA544DD1992CB6021").unwrap();
+        stats.record_decoded(&RuuviData::decode(&data).unwrap());
+        stats.record_failure(DecodeFailure::NoRuuviData);
+        stats.record_failure(DecodeFailure::NoRuuviData);
+
+        let mut metrics = MetricsWriter::new();
+        stats.collect_metrics(&mut metrics);
+        collect_build_info(&mut metrics);
+        let output = metrics.render(metrics::Format::OpenMetrics);
+        for line in [
+            r#"ruuvi_exporter_tags_decoded_total{format="5"} 1"#,
+            r#"ruuvi_exporter_tags_decoded_total{format="E1"} 0"#,
+            r#"ruuvi_exporter_tag_decode_failures_total{reason="no_ruuvi_data"} 2"#,
+            r#"ruuvi_exporter_tag_decode_failures_total{reason="bad_hex"} 0"#,
+            r#"ruuvi_exporter_build_info{version="0.1.0"} 1"#,
+        ] {
+            assert!(output.contains(&format!("{line}\n")), "{line}\n{output}");
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use futures_util::Stream;
+use parking_lot::RwLock;
+use serde::Deserialize;
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{
+    rustls::{
+        crypto::ring,
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+        ServerConfig,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+use tracing::{info, warn};
+
+use crate::reload;
+
+/// How long a client may take to complete the TLS handshake before its connection is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait before accepting again after an error, e.g. when file descriptors have run
+/// out, so the accept loop does not spin.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// `tls` section of the config file.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain, leaf certificate first
+    pub cert: PathBuf,
+    /// PEM file with the private key of the certificate
+    pub key: PathBuf,
+}
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("Failed to read {0:?}: {1}")]
+    Io(PathBuf, io::Error),
+    #[error("No certificates found in {0:?}")]
+    NoCertificates(PathBuf),
+    #[error("No private key found in {0:?}")]
+    NoPrivateKey(PathBuf),
+    #[error("Unsupported private key in {0:?}: {1}")]
+    InvalidKey(PathBuf, tokio_rustls::rustls::Error),
+}
+
+/// Loads the certificate chain and private key of `config`.
+fn load_certified_key(config: &TlsConfig) -> Result<CertifiedKey, TlsError> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|err| TlsError::Io(path.to_path_buf(), err))
+    };
+
+    let certs = rustls_pemfile::certs(&mut open(&config.cert)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| TlsError::Io(config.cert.clone(), err))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates(config.cert.clone()));
+    }
+
+    let key = rustls_pemfile::private_key(&mut open(&config.key)?)
+        .map_err(|err| TlsError::Io(config.key.clone(), err))?
+        .ok_or_else(|| TlsError::NoPrivateKey(config.key.clone()))?;
+    let key = ring::sign::any_supported_type(&key)
+        .map_err(|err| TlsError::InvalidKey(config.key.clone(), err))?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Server certificate that can be replaced at runtime without dropping connections.
+#[derive(Debug)]
+pub struct CertStore {
+    config: TlsConfig,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertStore {
+    pub fn load(config: TlsConfig) -> Result<Self, TlsError> {
+        let key = load_certified_key(&config)?;
+        Ok(Self {
+            config,
+            current: RwLock::new(Arc::new(key)),
+        })
+    }
+
+    /// Loads the certificate and key again. On failure the old certificate is kept.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let key = load_certified_key(&self.config)?;
+        *self.current.write() = Arc::new(key);
+        Ok(())
+    }
+
+    fn reload_and_log(&self) {
+        match self.reload() {
+            Ok(()) => info!("Reloaded TLS certificate"),
+            Err(err) => warn!("Failed to reload TLS certificate, keeping the old one: {err}"),
+        }
+    }
+
+    /// Builds the acceptor for new connections. Handshakes always use the current certificate.
+    pub fn acceptor(self: &Arc<Self>) -> TlsAcceptor {
+        let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("The default protocol versions are supported")
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        TlsAcceptor::from(Arc::new(config))
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().clone())
+    }
+}
+
+/// Reloads the certificate whenever the process receives SIGHUP or the files change.
+pub fn spawn_watchers(store: &Arc<CertStore>) {
+    let paths = vec![store.config.cert.clone(), store.config.key.clone()];
+    let store = store.clone();
+    reload::watch_files(paths, move || store.reload_and_log());
+}
+
+/// Accepts TLS connections on `listener`. Handshakes run concurrently, so a slow client does
+/// not hold up the others, and failed or stalled handshakes are dropped.
+pub fn incoming(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+) -> impl Stream<Item = io::Result<TlsStream<TcpStream>>> {
+    let (sender, receiver) = tokio::sync::mpsc::channel(32);
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    warn!("Failed to accept connection: {err}");
+                    tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            };
+            let acceptor = acceptor.clone();
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = sender.send(Ok(stream)).await;
+                    }
+                    Ok(Err(err)) => warn!("TLS handshake failed: {err}"),
+                    Err(_) => warn!("TLS handshake timed out after {HANDSHAKE_TIMEOUT:?}"),
+                }
+            });
+        }
+    });
+    futures_util::stream::unfold(receiver, |mut receiver| async move {
+        let stream = receiver.recv().await?;
+        Some((stream, receiver))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    /// Writes a new self-signed certificate and its key to temporary files.
+    fn write_certificate(cert: &NamedTempFile, key: &NamedTempFile) -> Vec<u8> {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(cert.path(), certified.cert.pem()).unwrap();
+        std::fs::write(key.path(), certified.key_pair.serialize_pem()).unwrap();
+        certified.cert.der().to_vec()
+    }
+
+    fn config(cert: &NamedTempFile, key: &NamedTempFile) -> TlsConfig {
+        TlsConfig {
+            cert: cert.path().to_path_buf(),
+            key: key.path().to_path_buf(),
+        }
+    }
+
+    fn current_cert(store: &CertStore) -> Vec<u8> {
+        store.current.read().cert[0].to_vec()
+    }
+
+    #[test]
+    fn test_reload_certificate() {
+        let (cert, key) = (NamedTempFile::new().unwrap(), NamedTempFile::new().unwrap());
+        let first = write_certificate(&cert, &key);
+        let store = CertStore::load(config(&cert, &key)).unwrap();
+        assert_eq!(current_cert(&store), first);
+
+        let second = write_certificate(&cert, &key);
+        store.reload().unwrap();
+        assert_eq!(current_cert(&store), second);
+
+        // A broken key keeps the previous certificate in use
+        std::fs::write(key.path(), "not a key").unwrap();
+        assert!(matches!(store.reload(), Err(TlsError::NoPrivateKey(_))));
+        assert_eq!(current_cert(&store), second);
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        let (cert, key) = (NamedTempFile::new().unwrap(), NamedTempFile::new().unwrap());
+        assert!(matches!(
+            CertStore::load(config(&cert, &key)),
+            Err(TlsError::NoCertificates(_))
+        ));
+
+        let missing = TlsConfig {
+            cert: PathBuf::from("/nonexistent/cert.pem"),
+            key: key.path().to_path_buf(),
+        };
+        assert!(matches!(CertStore::load(missing), Err(TlsError::Io(_, _))));
+    }
+}
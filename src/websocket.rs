@@ -0,0 +1,316 @@
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::debug;
+use warp::ws::{Message, WebSocket};
+
+use crate::api::{Reading, TagReport, FIELDS};
+use crate::mac::MacAddress;
+
+/// Message sent by a client.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ClientMessage {
+    /// Adds tags and fields to the subscription
+    Subscribe {
+        #[serde(default)]
+        macs: Vec<MacAddress>,
+        #[serde(default)]
+        fields: Vec<String>,
+    },
+    /// Removes tags and fields from the subscription
+    Unsubscribe {
+        #[serde(default)]
+        macs: Vec<MacAddress>,
+        #[serde(default)]
+        fields: Vec<String>,
+    },
+}
+
+/// Changed values of a subscribed tag.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TagDelta {
+    pub mac: MacAddress,
+    pub up: bool,
+    pub last_seen: f64,
+    pub rssi: i32,
+    /// Subscribed values that differ from the ones sent before
+    pub values: BTreeMap<&'static str, Reading>,
+}
+
+/// Message sent to a client.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// Current state of the tags, sent on connect with all tags and after every subscription
+    /// change with the subscribed tags
+    Snapshot {
+        tags: Vec<TagReport>,
+    },
+    Update(TagDelta),
+    Error {
+        message: String,
+    },
+}
+
+/// Subscription of a single client and the values it has been sent.
+///
+/// Deltas are sent for the subscribed tags only. If no fields are subscribed, deltas include
+/// all fields.
+#[derive(Debug, Default)]
+pub struct Session {
+    macs: HashSet<MacAddress>,
+    fields: HashSet<String>,
+    sent: HashMap<MacAddress, BTreeMap<&'static str, Reading>>,
+}
+
+/// Whether a session subscribed to `fields` is sent `field`.
+fn wants_field(fields: &HashSet<String>, field: &str) -> bool {
+    fields.is_empty() || fields.contains(field)
+}
+
+impl Session {
+    /// Applies a message from the client, or returns why it is invalid.
+    pub fn handle(&mut self, text: &str) -> Result<(), String> {
+        let message: ClientMessage = serde_json::from_str(text).map_err(|err| err.to_string())?;
+        let (ClientMessage::Subscribe { fields, .. } | ClientMessage::Unsubscribe { fields, .. }) =
+            &message;
+        if let Some(field) = fields
+            .iter()
+            .find(|field| !FIELDS.contains(&field.as_str()))
+        {
+            return Err(format!("Unknown field {field:?}"));
+        }
+
+        match message {
+            ClientMessage::Subscribe { macs, fields } => {
+                self.macs.extend(macs);
+                self.fields.extend(fields);
+            }
+            ClientMessage::Unsubscribe { macs, fields } => {
+                for mac in &macs {
+                    self.macs.remove(mac);
+                }
+                for field in &fields {
+                    self.fields.remove(field);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Builds the snapshot of the subscribed tags out of `reports`, the current state of all
+    /// tags, and takes it as the base of later deltas.
+    pub fn snapshot(&mut self, reports: Vec<TagReport>) -> ServerMessage {
+        self.sent.clear();
+        let mut tags = Vec::new();
+        for mut report in reports {
+            if !self.macs.contains(&report.mac) {
+                continue;
+            }
+            report
+                .values
+                .retain(|field, _| wants_field(&self.fields, field));
+            self.sent.insert(report.mac, report.values.clone());
+            tags.push(report);
+        }
+        ServerMessage::Snapshot { tags }
+    }
+
+    /// Returns the delta to send for a new reading, if the client is interested in it.
+    pub fn update(&mut self, report: &TagReport) -> Option<ServerMessage> {
+        if !self.macs.contains(&report.mac) {
+            return None;
+        }
+        let sent = self.sent.entry(report.mac).or_default();
+        let mut values = BTreeMap::new();
+        for (&field, &reading) in &report.values {
+            if wants_field(&self.fields, field) && sent.get(field) != Some(&reading) {
+                sent.insert(field, reading);
+                values.insert(field, reading);
+            }
+        }
+        Some(ServerMessage::Update(TagDelta {
+            mac: report.mac,
+            up: report.up,
+            last_seen: report.last_seen,
+            rssi: report.rssi,
+            values,
+        }))
+    }
+}
+
+async fn send(socket: &mut WebSocket, message: &ServerMessage) -> Result<(), warp::Error> {
+    let text = serde_json::to_string(message).expect("Server messages serialize to JSON");
+    socket.send(Message::text(text)).await
+}
+
+/// Serves a client until it disconnects. `snapshot` returns the current state of all tags.
+pub async fn serve(
+    mut socket: WebSocket,
+    mut updates: broadcast::Receiver<Arc<TagReport>>,
+    snapshot: impl Fn() -> Vec<TagReport>,
+) {
+    let mut session = Session::default();
+    let initial = ServerMessage::Snapshot { tags: snapshot() };
+    if send(&mut socket, &initial).await.is_err() {
+        return;
+    }
+
+    loop {
+        let reply = tokio::select! {
+            message = socket.next() => match message {
+                Some(Ok(message)) if message.is_text() => {
+                    let text = message.to_str().unwrap_or_default();
+                    match session.handle(text) {
+                        Ok(()) => Some(session.snapshot(snapshot())),
+                        Err(message) => Some(ServerMessage::Error { message }),
+                    }
+                }
+                Some(Ok(message)) if message.is_close() => break,
+                // Pings are answered by the websocket implementation
+                Some(Ok(_)) => None,
+                Some(Err(err)) => {
+                    debug!("WebSocket connection failed: {err}");
+                    break;
+                }
+                None => break,
+            },
+            update = updates.recv() => match update {
+                Ok(report) => session.update(&report),
+                // Missed deltas, start over from the current state
+                Err(RecvError::Lagged(_)) => Some(session.snapshot(snapshot())),
+                Err(RecvError::Closed) => break,
+            },
+        };
+        if let Some(reply) = reply {
+            if send(&mut socket, &reply).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mac(s: &str) -> MacAddress {
+        s.parse().unwrap()
+    }
+
+    fn report(mac_address: &str, temperature: f64, humidity: f64) -> TagReport {
+        let reading = |value, unit| Reading {
+            value,
+            unit: Some(unit),
+        };
+        TagReport {
+            mac: mac(mac_address),
+            name: None,
+            labels: BTreeMap::new(),
+            data_format: "5",
+            last_seen: 1000.0,
+            last_received: 1000.0,
+            up: true,
+            rssi: -60,
+            best_gateway: mac("11:11:11:11:11:11"),
+            gateways: vec![],
+            values: BTreeMap::from([
+                ("temperature", reading(temperature, "°C")),
+                ("humidity", reading(humidity, "%")),
+            ]),
+        }
+    }
+
+    fn delta_values(message: Option<ServerMessage>) -> Vec<&'static str> {
+        match message {
+            Some(ServerMessage::Update(delta)) => delta.values.into_keys().collect(),
+            other => panic!("Expected an update, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_subscription() {
+        let mut session = Session::default();
+        let kitchen = "AA:AA:AA:AA:AA:AA";
+        // Nothing is sent before subscribing
+        assert_eq!(session.update(&report(kitchen, 20.0, 40.0)), None);
+
+        session
+            .handle(r#"{"type": "subscribe", "macs": ["aa:aa:aa:aa:aa:aa"]}"#)
+            .unwrap();
+        let snapshot = session.snapshot(vec![
+            report(kitchen, 20.0, 40.0),
+            report("BB:BB:BB:BB:BB:BB", 5.0, 80.0),
+        ]);
+        let ServerMessage::Snapshot { tags } = snapshot else {
+            panic!("Expected a snapshot");
+        };
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0].values.len(), 2);
+
+        // Only changed values are sent
+        assert_eq!(
+            delta_values(session.update(&report(kitchen, 20.5, 40.0))),
+            ["temperature"]
+        );
+        assert!(delta_values(session.update(&report(kitchen, 20.5, 40.0))).is_empty());
+        assert_eq!(
+            session.update(&report("BB:BB:BB:BB:BB:BB", 6.0, 80.0)),
+            None
+        );
+
+        // Restricting the fields
+        session
+            .handle(r#"{"type": "subscribe", "fields": ["humidity"]}"#)
+            .unwrap();
+        session.snapshot(vec![report(kitchen, 20.5, 40.0)]);
+        assert_eq!(
+            delta_values(session.update(&report(kitchen, 21.0, 41.0))),
+            ["humidity"]
+        );
+
+        session
+            .handle(r#"{"type": "unsubscribe", "macs": ["AA:AA:AA:AA:AA:AA"]}"#)
+            .unwrap();
+        assert_eq!(session.update(&report(kitchen, 22.0, 42.0)), None);
+    }
+
+    #[test]
+    fn test_invalid_messages() {
+        let mut session = Session::default();
+        assert!(session.handle("subscribe").is_err());
+        assert!(session.handle(r#"{"type": "publish"}"#).is_err());
+        assert!(session
+            .handle(r#"{"type": "subscribe", "macs": ["kitchen"]}"#)
+            .is_err());
+        assert_eq!(
+            session.handle(r#"{"type": "subscribe", "fields": ["temp"]}"#),
+            Err(r#"Unknown field "temp""#.to_string())
+        );
+    }
+
+    #[test]
+    fn test_message_format() {
+        let message = ServerMessage::Error {
+            message: "oops".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_string(&message).unwrap(),
+            r#"{"type":"error","message":"oops"}"#
+        );
+        let mut session = Session::default();
+        session
+            .handle(r#"{"type": "subscribe", "macs": ["AA:AA:AA:AA:AA:AA"]}"#)
+            .unwrap();
+        let update = session.update(&report("AA:AA:AA:AA:AA:AA", 20.0, 40.0));
+        let json = serde_json::to_value(update.unwrap()).unwrap();
+        assert_eq!(json["type"], "update");
+        assert_eq!(json["mac"], "AA:AA:AA:AA:AA:AA");
+        assert_eq!(json["values"]["temperature"]["value"], 20.0);
+    }
+}